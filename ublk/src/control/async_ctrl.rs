@@ -0,0 +1,567 @@
+// SPDX-License-Identifier: MIT
+
+use crate::control::{
+    quiesce_timeout_ms, sys, CmdEncoding, CtrlOp, CtrlRequest, CtrlTransport, DeviceFlags,
+    DeviceInfo, DeviceOptions, DeviceParams, UringTransport,
+};
+use crate::error::{Error, Result};
+use std::any::Any;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Asynchronous control object
+///
+/// The async counterpart of [`UblkCtrl`](crate::control::UblkCtrl), it does not depend on any particular
+/// executor: the control commands are sent through the [`CtrlTransport`] when
+/// the futures are first polled, and a background thread wakes up each future
+/// once its command completes. Any number of commands can be in flight at the
+/// same time, a slow command doesn't delay the completion of the others.
+pub struct UblkCtrlAsync {
+    inner: Arc<Inner>,
+    reaper: Option<JoinHandle<()>>,
+    encoding: CmdEncoding,
+    features: DeviceFlags,
+    // whether the known devices are unprivileged, see `UblkCtrl`
+    unprivileged: Mutex<HashMap<u32, bool>>,
+}
+
+impl UblkCtrlAsync {
+    /// Async control object constructor
+    /// # Errors
+    ///
+    pub fn new() -> Result<Self> {
+        Self::with_transport(UringTransport::new()?)
+    }
+
+    /// Async control object sending the commands through `transport`,
+    /// see [`UblkCtrl::with_transport()`](crate::control::UblkCtrl::with_transport)
+    /// # Errors
+    ///
+    /// Fails if the thread receiving the completions cannot be spawned.
+    pub fn with_transport<T: CtrlTransport + 'static>(transport: T) -> Result<Self> {
+        // Kernels without `GetFeatures` only support legacy opcodes
+        let mut features: sys::Features = 0;
+        let cmd = sys::CtrlCmd::new(
+            CtrlOp::GetFeatures,
+            sys::DevInfo::NEW_DEV_ID,
+            CmdEncoding::Ioctl,
+        )
+        .buffer(&mut features);
+        let supported = matches!(
+            transport.execute(&mut [CtrlRequest::new(cmd)]).as_deref(),
+            Ok([res]) if *res >= 0
+        );
+        let features = if supported {
+            DeviceFlags::from_bits_truncate(features)
+        } else {
+            DeviceFlags::empty()
+        };
+        let encoding = if features.contains(DeviceFlags::CmdIoctlEncode) {
+            CmdEncoding::Ioctl
+        } else {
+            CmdEncoding::Legacy
+        };
+
+        let inner = Arc::new(Inner {
+            transport: Box::new(transport),
+            encoding,
+            inflight: Mutex::new(HashMap::new()),
+            submitted: Condvar::new(),
+            uniq: AtomicU64::new(0),
+            shutdown: AtomicBool::new(false),
+        });
+
+        let reaper = {
+            let inner = Arc::clone(&inner);
+            thread::Builder::new()
+                .name("ublk-ctrl".to_string())
+                .spawn(move || inner.reap_commands())?
+        };
+
+        Ok(Self {
+            inner,
+            reaper: Some(reaper),
+            encoding,
+            features,
+            unprivileged: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Add new device
+    /// # Errors
+    ///
+    pub async fn add_device(&self, options: &DeviceOptions) -> Result<DeviceInfo> {
+        let mut options = *options;
+        if options.negotiate_features {
+            options.flags &= self.get_features().await?;
+        }
+
+        let info: sys::DevInfo = (&options).into();
+
+        // The kernel driver fails if info.dev_id != cmd.dev_id
+        let res = self
+            .submit(CtrlOp::AddDev, options.dev_id, 0, Some(info))?
+            .await;
+
+        match res {
+            Ok(info) => {
+                let info: DeviceInfo = info.into();
+                self.lock_unprivileged()
+                    .insert(info.dev_id, info.flags.contains(DeviceFlags::Unprivileged));
+                Ok(info)
+            }
+            Err(err) => {
+                let supported = self.get_features().await.ok();
+                let rejected = supported.map(|supported| options.flags - supported);
+                match rejected {
+                    Some(rejected) if !rejected.is_empty() => {
+                        Err(Error::UnsupportedFeatures(rejected))
+                    }
+                    _ => Err(err),
+                }
+            }
+        }
+    }
+
+    /// Get the features supported by the kernel driver
+    /// # Errors
+    ///
+    pub async fn get_features(&self) -> Result<DeviceFlags> {
+        let features: sys::Features = 0;
+
+        let features = self
+            .submit(
+                CtrlOp::GetFeatures,
+                sys::DevInfo::NEW_DEV_ID,
+                0,
+                Some(features),
+            )?
+            .await?;
+        Ok(DeviceFlags::from_bits_truncate(features))
+    }
+
+    /// Delete a device
+    /// # Errors
+    ///
+    pub async fn delete_device(&self, dev_id: u32) -> Result<()> {
+        self.device_cmd::<()>(CtrlOp::DelDev, dev_id, 0, None)
+            .await?;
+        self.lock_unprivileged().remove(&dev_id);
+        Ok(())
+    }
+
+    /// Start the ublksrv device, see [`UblkCtrl::start_device()`](crate::control::UblkCtrl::start_device)
+    /// # Errors
+    ///
+    pub async fn start_device(&self, dev_id: u32, pid: u64) -> Result<()> {
+        self.device_cmd::<()>(CtrlOp::StartDev, dev_id, pid, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Stop the ublksrv device, see [`UblkCtrl::stop_device()`](crate::control::UblkCtrl::stop_device)
+    /// # Errors
+    ///
+    pub async fn stop_device(&self, dev_id: u32) -> Result<()> {
+        self.device_cmd::<()>(CtrlOp::StopDev, dev_id, 0, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Set the device parameters
+    /// Parameters can only be changed when device isn't live
+    /// # Errors
+    ///
+    pub async fn set_device_parameters(&self, dev_id: u32, params: &DeviceParams) -> Result<()> {
+        let params: sys::DevParams = params.into();
+
+        self.device_cmd(CtrlOp::SetParams, dev_id, 0, Some(params))
+            .await?;
+        Ok(())
+    }
+
+    /// Get the device parameters
+    /// # Errors
+    ///
+    pub async fn get_device_parameters(&self, dev_id: u32) -> Result<DeviceParams> {
+        let params = sys::DevParams::empty();
+
+        let params = self
+            .device_cmd(CtrlOp::GetParams, dev_id, 0, Some(params))
+            .await?
+            .unwrap_or(params);
+        Ok(params.into())
+    }
+
+    /// Change the size of a live device, see [`UblkCtrl::update_size()`](crate::control::UblkCtrl::update_size)
+    /// # Errors
+    ///
+    pub async fn update_size(&self, dev_id: u32, sectors: u64) -> Result<()> {
+        self.device_cmd::<()>(CtrlOp::UpdateSize, dev_id, sectors, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Get device's queue affinity
+    /// # Errors
+    ///
+    pub async fn get_queue_affinity(&self, dev_id: u32, queue: u16) -> Result<libc::cpu_set_t> {
+        // SAFETY: all-zero byte-pattern represents a valid libc::cpu_set_t
+        let cpu_set: libc::cpu_set_t = unsafe { mem::zeroed() };
+
+        let res = self
+            .device_cmd(
+                CtrlOp::GetQueueAffinity,
+                dev_id,
+                u64::from(queue),
+                Some(cpu_set),
+            )
+            .await?;
+        Ok(res.unwrap_or(cpu_set))
+    }
+
+    /// Start the user recovery of a quiesced device,
+    /// see [`UblkCtrl::start_user_recovery()`](crate::control::UblkCtrl::start_user_recovery)
+    /// # Errors
+    ///
+    pub async fn start_user_recovery(&self, dev_id: u32) -> Result<()> {
+        self.device_cmd::<()>(CtrlOp::StartUserRecovery, dev_id, 0, None)
+            .await?;
+        Ok(())
+    }
+
+    /// End the user recovery of a device, see [`UblkCtrl::end_user_recovery()`](crate::control::UblkCtrl::end_user_recovery)
+    /// # Errors
+    ///
+    pub async fn end_user_recovery(&self, dev_id: u32, pid: u64) -> Result<()> {
+        self.device_cmd::<()>(CtrlOp::EndUserRecovery, dev_id, pid, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Quiesce a live device, see [`UblkCtrl::quiesce_device()`](crate::control::UblkCtrl::quiesce_device)
+    /// # Errors
+    ///
+    pub async fn quiesce_device(&self, dev_id: u32, timeout: Option<Duration>) -> Result<()> {
+        self.device_cmd::<()>(
+            CtrlOp::QuiesceDev,
+            dev_id,
+            quiesce_timeout_ms(timeout),
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Get the device information, see [`UblkCtrl::get_device_info()`](crate::control::UblkCtrl::get_device_info)
+    /// # Errors
+    ///
+    pub async fn get_device_info(&self, dev_id: u32) -> Result<DeviceInfo> {
+        if !self.features.contains(DeviceFlags::Unprivileged) {
+            let info = self
+                .submit(CtrlOp::GetDevInfo, dev_id, 0, Some(sys::DevInfo::new()))?
+                .await?;
+            return Ok(info.into());
+        }
+
+        // `GetDevInfo2` always carries the device's char device path
+        let buf = sys::DevPathBuffer::new(dev_id, Some(&sys::DevInfo::new()));
+        let buf = self
+            .submit(CtrlOp::GetDevInfo2, dev_id, 0, Some(buf))?
+            .await?;
+
+        let info: DeviceInfo = buf.payload().unwrap_or_default().into();
+        self.lock_unprivileged()
+            .insert(dev_id, info.flags.contains(DeviceFlags::Unprivileged));
+        Ok(info)
+    }
+
+    /// Returns the encoding of the control commands opcodes in use
+    #[must_use]
+    pub const fn cmd_encoding(&self) -> CmdEncoding {
+        self.encoding
+    }
+
+    fn lock_unprivileged(&self) -> MutexGuard<'_, HashMap<u32, bool>> {
+        self.unprivileged
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Sends a command to the device `dev_id`, prefixing its buffer (if any) with
+    // the device's char device path when required, see `UblkCtrl::needs_dev_path()`
+    async fn device_cmd<B: Copy + Send + 'static>(
+        &self,
+        op: CtrlOp,
+        dev_id: u32,
+        data: u64,
+        buf: Option<B>,
+    ) -> Result<Option<B>> {
+        let dev_path = if self.features.contains(DeviceFlags::Unprivileged) {
+            let unprivileged = self.lock_unprivileged().get(&dev_id).copied();
+            match unprivileged {
+                Some(unprivileged) => unprivileged,
+                None => self
+                    .get_device_info(dev_id)
+                    .await?
+                    .flags
+                    .contains(DeviceFlags::Unprivileged),
+            }
+        } else {
+            false
+        };
+
+        if dev_path {
+            let buf = sys::DevPathBuffer::new(dev_id, buf.as_ref());
+            let buf = self.submit(op, dev_id, data, Some(buf))?.await?;
+            return Ok(buf.payload());
+        }
+
+        match buf {
+            Some(buf) => Ok(Some(self.submit(op, dev_id, data, Some(buf))?.await?)),
+            None => {
+                self.submit::<()>(op, dev_id, data, None)?.await?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Submits the command, the returned future resolves to the command's buffer
+    // (if any) once the kernel driver completes it.
+    fn submit<B: CmdBuffer>(
+        &self,
+        op: CtrlOp,
+        dev_id: u32,
+        data: u64,
+        buf: Option<B>,
+    ) -> Result<CtrlFuture<'_, B>> {
+        let uniq = self.inner.uniq.fetch_add(1, Ordering::Relaxed) + 1;
+
+        // The entry is added before submitting, so the completion always finds it
+        let mut inflight = self.inner.lock_inflight();
+        let entry = inflight.entry(uniq).or_insert(Inflight {
+            op,
+            dev_id,
+            buffer: buf.map(|buf| Box::new(buf) as Box<dyn CmdBuffer>),
+            result: None,
+            waker: None,
+            orphan: false,
+        });
+
+        let cmd = sys::CtrlCmd::new(op, dev_id, self.encoding).data(data);
+        let cmd = match entry.buffer.as_deref_mut() {
+            Some(buf) => buf.attach(cmd),
+            None => cmd,
+        };
+        // SAFETY: the buffer is owned by the entry until the command completes,
+        // so it remains valid even if the future is dropped before that.
+        let submitted = unsafe {
+            self.inner
+                .transport
+                .submit(uniq, &mut CtrlRequest::new(cmd))
+        };
+        if let Err(err) = submitted {
+            inflight.remove(&uniq);
+            return Err(err);
+        }
+        self.inner.submitted.notify_one();
+        drop(inflight);
+
+        Ok(CtrlFuture {
+            inner: &self.inner,
+            uniq,
+            buf: PhantomData,
+        })
+    }
+}
+
+impl Drop for UblkCtrlAsync {
+    fn drop(&mut self) {
+        // The reaper exits once the commands in flight complete
+        self.inner.shutdown.store(true, Ordering::Relaxed);
+        {
+            let _inflight = self.inner.lock_inflight();
+            self.inner.submitted.notify_one();
+        }
+
+        if let Some(reaper) = self.reaper.take() {
+            let _ = reaper.join();
+        }
+    }
+}
+
+// Command buffer owned by an in-flight command, attached to the command as plain
+// data or prefixed with the device's char device path
+trait CmdBuffer: Send + 'static {
+    fn attach<'a>(&'a mut self, cmd: sys::CtrlCmd<'a>) -> sys::CtrlCmd<'a>;
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send>;
+}
+
+impl<T: Copy + Send + 'static> CmdBuffer for T {
+    fn attach<'a>(&'a mut self, cmd: sys::CtrlCmd<'a>) -> sys::CtrlCmd<'a> {
+        cmd.buffer(self)
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send> {
+        self
+    }
+}
+
+impl<T: Send + 'static> CmdBuffer for sys::DevPathBuffer<T> {
+    fn attach<'a>(&'a mut self, cmd: sys::CtrlCmd<'a>) -> sys::CtrlCmd<'a> {
+        cmd.dev_path_buffer(self)
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any + Send> {
+        self
+    }
+}
+
+struct Inflight {
+    op: CtrlOp,
+    dev_id: u32,
+    buffer: Option<Box<dyn CmdBuffer>>,
+    result: Option<Result<()>>,
+    waker: Option<Waker>,
+    // the future was dropped before the command completed
+    orphan: bool,
+}
+
+struct Inner {
+    transport: Box<dyn CtrlTransport>,
+    encoding: CmdEncoding,
+    inflight: Mutex<HashMap<u64, Inflight>>,
+    // signaled when a command is submitted, or on shutdown
+    submitted: Condvar,
+    uniq: AtomicU64,
+    shutdown: AtomicBool,
+}
+
+impl Inner {
+    fn lock_inflight(&self) -> MutexGuard<'_, HashMap<u64, Inflight>> {
+        self.inflight.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Receives the completions and wakes up the futures waiting for them as
+    // they come, until the control object is dropped
+    fn reap_commands(&self) {
+        loop {
+            {
+                let mut inflight = self.lock_inflight();
+                while !inflight.values().any(|entry| entry.result.is_none()) {
+                    if self.shutdown.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    inflight = self
+                        .submitted
+                        .wait(inflight)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+            }
+
+            match self.transport.reap() {
+                Ok(completions) => {
+                    let mut inflight = self.lock_inflight();
+                    for (uniq, res) in completions {
+                        let Some(entry) = inflight.get_mut(&uniq) else {
+                            continue;
+                        };
+                        let cmd = sys::CtrlCmd::new(entry.op, entry.dev_id, self.encoding);
+                        self.complete(&mut inflight, uniq, cmd.result(res));
+                    }
+                }
+                Err(err) => {
+                    // All the commands in flight fail with the transport's errno
+                    let errno = err.raw_os_error().unwrap_or(libc::EIO);
+                    let mut inflight = self.lock_inflight();
+                    let pending: Vec<_> = inflight
+                        .iter()
+                        .filter(|(_, entry)| entry.result.is_none())
+                        .map(|(&uniq, _)| uniq)
+                        .collect();
+                    for uniq in pending {
+                        let res = Err(io::Error::from_raw_os_error(errno).into());
+                        self.complete(&mut inflight, uniq, res);
+                    }
+                }
+            }
+        }
+    }
+
+    // Stores the command's result and wakes up its future, if any
+    fn complete(&self, inflight: &mut HashMap<u64, Inflight>, uniq: u64, res: Result<()>) {
+        let Some(entry) = inflight.get_mut(&uniq) else {
+            return;
+        };
+
+        if entry.orphan {
+            inflight.remove(&uniq);
+            return;
+        }
+
+        entry.result = Some(res);
+        if let Some(waker) = entry.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+// Future that resolves when the control command completes
+struct CtrlFuture<'a, B> {
+    inner: &'a Inner,
+    uniq: u64,
+    buf: PhantomData<B>,
+}
+
+impl<B: 'static> Future for CtrlFuture<'_, B> {
+    type Output = Result<B>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inflight = self.inner.lock_inflight();
+        let entry = inflight
+            .get_mut(&self.uniq)
+            .expect("in-flight control command");
+
+        if entry.result.is_none() {
+            entry.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let entry = inflight
+            .remove(&self.uniq)
+            .expect("in-flight control command");
+        if let Some(Err(err)) = entry.result {
+            return Poll::Ready(Err(err));
+        }
+
+        let buf = entry
+            .buffer
+            .map_or_else(|| Box::new(()) as Box<dyn Any>, |buf| buf.into_any())
+            .downcast::<B>()
+            .expect("control command buffer type");
+        Poll::Ready(Ok(*buf))
+    }
+}
+
+impl<B> Drop for CtrlFuture<'_, B> {
+    fn drop(&mut self) {
+        let mut inflight = self.inner.lock_inflight();
+        if let Some(entry) = inflight.get_mut(&self.uniq) {
+            if entry.result.is_some() {
+                inflight.remove(&self.uniq);
+            } else {
+                entry.orphan = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+// SPDX-License-Identifier: MIT
+
+// The flags are (de)serialized as lists of names, e.g., ["UserCopy", "Zoned"],
+// so they don't depend on the kernel ABI values. Unknown bits are not serialized.
+
+use crate::control::{DeviceAttr, DeviceFlags};
+use bitflags::Flags;
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeSeq, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+
+fn serialize_flags<F: Flags, S: Serializer>(flags: &F, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut seq = serializer.serialize_seq(Some(flags.iter_names().count()))?;
+    for (name, _) in flags.iter_names() {
+        seq.serialize_element(name)?;
+    }
+    seq.end()
+}
+
+fn deserialize_flags<'de, F: Flags, D: Deserializer<'de>>(deserializer: D) -> Result<F, D::Error> {
+    deserializer.deserialize_seq(FlagsVisitor(PhantomData))
+}
+
+struct FlagsVisitor<F>(PhantomData<F>);
+
+impl<'de, F: Flags> Visitor<'de> for FlagsVisitor<F> {
+    type Value = F;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a list of flag names")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<F, A::Error> {
+        let mut flags = F::empty();
+        while let Some(name) = seq.next_element::<String>()? {
+            let flag = F::from_name(&name)
+                .ok_or_else(|| de::Error::custom(format!("unknown flag `{}`", name)))?;
+            flags.insert(flag);
+        }
+        Ok(flags)
+    }
+}
+
+impl Serialize for DeviceFlags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_flags(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceFlags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_flags(deserializer)
+    }
+}
+
+impl Serialize for DeviceAttr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_flags(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceAttr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize_flags(deserializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::control::{
+        DeviceAttr, DeviceFlags, DeviceInfo, DeviceOptions, DeviceParamDevt, DeviceParamDiscard,
+        DeviceParamSegment, DeviceParamZoned, DeviceParams, DeviceState,
+    };
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::fmt::Debug;
+
+    fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: &T) -> String {
+        let json = serde_json::to_string(value).unwrap();
+        assert_eq!(&serde_json::from_str::<T>(&json).unwrap(), value);
+        json
+    }
+
+    #[test]
+    fn device_flags() {
+        assert_eq!(round_trip(&DeviceFlags::empty()), "[]");
+        assert_eq!(
+            round_trip(&(DeviceFlags::UserCopy | DeviceFlags::Zoned)),
+            r#"["UserCopy","Zoned"]"#
+        );
+        round_trip(&DeviceFlags::all());
+    }
+
+    #[test]
+    fn device_attr() {
+        assert_eq!(round_trip(&DeviceAttr::empty()), "[]");
+        assert_eq!(
+            round_trip(&(DeviceAttr::ReadOnly | DeviceAttr::Fua)),
+            r#"["ReadOnly","Fua"]"#
+        );
+        round_trip(&DeviceAttr::all());
+    }
+
+    #[test]
+    fn unknown_bits_are_dropped() {
+        let flags = DeviceFlags::from_bits_retain(DeviceFlags::UserCopy.bits() | 1 << 63);
+        let json = serde_json::to_string(&flags).unwrap();
+        assert_eq!(json, r#"["UserCopy"]"#);
+    }
+
+    #[test]
+    fn unknown_flag_name() {
+        let err = serde_json::from_str::<DeviceFlags>(r#"["UserCopy","Bogus"]"#).unwrap_err();
+        assert!(err.to_string().contains("unknown flag `Bogus`"), "{err}");
+
+        let err = serde_json::from_str::<DeviceAttr>(r#"["ReadOnly","UserCopy"]"#).unwrap_err();
+        assert!(err.to_string().contains("unknown flag `UserCopy`"), "{err}");
+    }
+
+    #[test]
+    fn flags_not_a_list() {
+        assert!(serde_json::from_str::<DeviceFlags>("5").is_err());
+        assert!(serde_json::from_str::<DeviceAttr>(r#""ReadOnly""#).is_err());
+    }
+
+    #[test]
+    fn device_info() {
+        for state in [
+            DeviceState::Dead,
+            DeviceState::Live,
+            DeviceState::Quiesced,
+            DeviceState::Unknown(7),
+        ] {
+            round_trip(&DeviceInfo {
+                dev_id: 3,
+                srv_pid: 1234,
+                active: state == DeviceState::Live,
+                state,
+                nr_hw_queues: 2,
+                queue_depth: 128,
+                max_io_buf_bytes: 512 << 10,
+                flags: DeviceFlags::UserCopy | DeviceFlags::Unprivileged,
+                owner_uid: 1000,
+                owner_gid: 1000,
+            });
+        }
+    }
+
+    #[test]
+    fn device_options() {
+        round_trip(&DeviceOptions::new());
+        round_trip(
+            &DeviceOptions::new()
+                .device_id(4)
+                .nr_hw_queues(4)
+                .queue_depth(64)
+                .max_io_buf_bytes(1 << 20)
+                .flags(DeviceFlags::UserRecovery | DeviceFlags::Quiesce)
+                .negotiate_features(true),
+        );
+    }
+
+    #[test]
+    fn device_params() {
+        round_trip(&DeviceParams::default());
+        round_trip(&DeviceParams {
+            attrs: DeviceAttr::VolatileCache | DeviceAttr::Fua,
+            logical_bs_shift: 9,
+            physical_bs_shift: 12,
+            io_opt_shift: 12,
+            io_min_shift: 9,
+            max_sectors: 1024,
+            chunk_sectors: 2048,
+            dev_sectors: 1 << 21,
+            virt_boundary_mask: 4095,
+            discard: Some(DeviceParamDiscard {
+                discard_alignment: 0,
+                discard_granularity: 4096,
+                max_discard_sectors: 1024,
+                max_write_zeroes_sectors: 1024,
+                max_discard_segments: 1,
+            }),
+            devt: Some(DeviceParamDevt {
+                char_major: 511,
+                char_minor: 0,
+                disk_major: 259,
+                disk_minor: 0,
+            }),
+            zoned: Some(DeviceParamZoned {
+                max_open_zones: 8,
+                max_active_zones: 16,
+                max_zone_append_sectors: 256,
+            }),
+            dma_alignment: Some(511),
+            segment: Some(DeviceParamSegment {
+                seg_boundary_mask: 4095,
+                max_segment_size: 4096,
+                max_segments: 128,
+            }),
+        });
+    }
+}
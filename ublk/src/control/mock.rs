@@ -0,0 +1,866 @@
+// SPDX-License-Identifier: MIT
+
+use crate::control::{
+    sys, CtrlOp, CtrlRequest, CtrlTransport, DeviceFlags, DeviceInfo, DeviceParamDevt,
+    DeviceParams, DeviceState,
+};
+use crate::error::Result;
+use crate::queue::{self, MockCharDevice, UblkQueue};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::{mem, ptr};
+
+/// Control transport emulating the kernel driver in-process
+///
+/// It keeps the devices in memory and implements the driver's state machine:
+/// device id allocation, add/start/stop/delete, the parameters checks, the user
+/// recovery, quiesce and resize, failing the commands with the driver's errno.
+///
+/// Each device has an emulated char device, see [`char_device()`](Self::char_device),
+/// but the devices are started without waiting for their queues, so the control
+/// paths can be exercised without the ublk module, see
+/// [`UblkCtrl::with_transport()`](crate::control::UblkCtrl::with_transport).
+///
+/// Clones share the emulated driver, so the devices of a control object owning
+/// the transport can still be inspected, and its commands held, through a clone.
+#[derive(Debug, Clone)]
+pub struct MockTransport {
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    driver: Mutex<MockDriver>,
+    // signaled when commands complete
+    completed: Condvar,
+}
+
+#[derive(Debug)]
+struct MockDriver {
+    features: DeviceFlags,
+    owner_uid: u32,
+    owner_gid: u32,
+    devices: BTreeMap<u32, MockDevice>,
+    // the commands held until released, see `MockTransport::hold()`
+    held_ops: Vec<CtrlOp>,
+    held: Vec<(u64, CtrlRequest<'static>)>,
+    completions: Vec<(u64, i32)>,
+}
+
+#[derive(Debug)]
+struct MockDevice {
+    info: DeviceInfo,
+    params: Option<DeviceParams>,
+    // between StartUserRecovery and EndUserRecovery
+    recovering: bool,
+    // none on AutoBufReg devices, not supported by the mock
+    cdev: Option<MockCharDevice>,
+}
+
+impl MockTransport {
+    /// Major number of the emulated char devices
+    pub const CHAR_MAJOR: u32 = 511;
+    /// Major number of the emulated block devices
+    pub const DISK_MAJOR: u32 = 259;
+
+    /// Mock of a kernel driver supporting `features`
+    ///
+    /// The devices are owned by the current user.
+    #[must_use]
+    pub fn new(features: DeviceFlags) -> Self {
+        let driver = MockDriver {
+            features,
+            // SAFETY: getuid() and getgid() have no memory safety requirements.
+            owner_uid: unsafe { libc::getuid() },
+            owner_gid: unsafe { libc::getgid() },
+            devices: BTreeMap::new(),
+            held_ops: Vec::new(),
+            held: Vec::new(),
+            completions: Vec::new(),
+        };
+
+        Self {
+            shared: Arc::new(Shared {
+                driver: Mutex::new(driver),
+                completed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Information of the device `dev_id`, if it exists
+    #[must_use]
+    pub fn device_info(&self, dev_id: u32) -> Option<DeviceInfo> {
+        self.lock_driver().devices.get(&dev_id).map(|dev| dev.info)
+    }
+
+    /// Ids of the existing devices, in increasing order
+    #[must_use]
+    pub fn device_ids(&self) -> Vec<u32> {
+        self.lock_driver().devices.keys().copied().collect()
+    }
+
+    /// Emulated char device of the device `dev_id`, to serve its queues with
+    /// [`UblkQueue::with_mock()`](crate::queue::UblkQueue::with_mock)
+    ///
+    /// Stopping the device aborts it, quiescing the device aborts the queues'
+    /// fetch commands until the user recovery starts. Returns `None` if the
+    /// device doesn't exist, or on [`DeviceFlags::AutoBufReg`] devices.
+    #[must_use]
+    pub fn char_device(&self, dev_id: u32) -> Option<MockCharDevice> {
+        self.lock_driver()
+            .devices
+            .get(&dev_id)
+            .and_then(|dev| dev.cdev.clone())
+    }
+
+    /// Emulates the death of the server of the live device `dev_id`
+    ///
+    /// With [`DeviceFlags::UserRecovery`] the device is quiesced, waiting to be
+    /// recovered, otherwise it is stopped. Returns `false` if the device is not live.
+    pub fn kill_server(&self, dev_id: u32) -> bool {
+        let mut driver = self.lock_driver();
+        let Some(dev) = driver.devices.get_mut(&dev_id) else {
+            return false;
+        };
+        if dev.info.state != DeviceState::Live {
+            return false;
+        }
+
+        if dev.info.flags.contains(DeviceFlags::UserRecovery) {
+            dev.quiesce();
+        } else {
+            dev.stop();
+        }
+        true
+    }
+
+    /// Holds the `op` commands sent from now on: they are neither executed nor
+    /// completed until [`release()`](Self::release), e.g., to emulate a slow
+    /// [`CtrlOp::StopDev`]
+    pub fn hold(&self, op: CtrlOp) {
+        let mut driver = self.lock_driver();
+        if !driver.held_ops.contains(&op) {
+            driver.held_ops.push(op);
+        }
+    }
+
+    /// Stops holding the `op` commands, and executes and completes the held ones
+    /// in the order they were sent
+    pub fn release(&self, op: CtrlOp) {
+        let mut driver = self.lock_driver();
+        driver.held_ops.retain(|&held| held != op);
+
+        let (mut released, held) = mem::take(&mut driver.held)
+            .into_iter()
+            .partition(|(_, req)| req.op() == op);
+        driver.held = held;
+        for (user_data, req) in &mut released {
+            let res = driver.handle(req);
+            driver.completions.push((*user_data, res));
+        }
+        self.shared.completed.notify_all();
+    }
+
+    /// Number of the commands held, see [`hold()`](Self::hold)
+    #[must_use]
+    pub fn nr_held(&self) -> usize {
+        self.lock_driver().held.len()
+    }
+
+    fn lock_driver(&self) -> MutexGuard<'_, MockDriver> {
+        self.shared
+            .driver
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl MockDriver {
+    fn handle(&mut self, req: &mut CtrlRequest<'_>) -> i32 {
+        match req.op() {
+            CtrlOp::GetFeatures => write_payload(req.buffer_mut(), &self.features.bits()),
+            CtrlOp::AddDev => self.add_dev(req),
+            op => {
+                let dev_id = req.dev_id();
+                let Some(flags) = self.devices.get(&dev_id).map(|dev| dev.info.flags) else {
+                    return -libc::ENODEV;
+                };
+
+                // The char device path prefixes the payload on unprivileged
+                // devices, and on GetDevInfo2 regardless of the device's kind
+                let path_len = usize::from(req.dev_path_len());
+                if flags.contains(DeviceFlags::Unprivileged) || op == CtrlOp::GetDevInfo2 {
+                    let path = format!("{}{}", UblkQueue::CDEV_PATH_PREFIX, dev_id);
+                    if req.buffer().get(..path_len) != Some(path.as_bytes()) {
+                        return -libc::EINVAL;
+                    }
+                }
+
+                if op == CtrlOp::DelDev {
+                    self.devices.remove(&dev_id);
+                    return 0;
+                }
+
+                let data = req.data();
+                let Some(buf) = req.buffer_mut().get_mut(path_len..) else {
+                    return -libc::EINVAL;
+                };
+                self.devices
+                    .get_mut(&dev_id)
+                    .map_or(-libc::ENODEV, |dev| dev.handle(op, data, buf))
+            }
+        }
+    }
+
+    fn add_dev(&mut self, req: &mut CtrlRequest<'_>) -> i32 {
+        let Some(info) = read_payload::<sys::DevInfo>(req.buffer()) else {
+            return -libc::EINVAL;
+        };
+        let mut info = DeviceInfo::from(info);
+
+        if req.queue_id() != u16::MAX || info.dev_id != req.dev_id() {
+            return -libc::EINVAL;
+        }
+
+        if !(1..=sys::DevInfo::MAX_NR_HW_QUEUES).contains(&info.nr_hw_queues)
+            || !(1..=sys::DevInfo::MAX_QUEUE_DEPTH).contains(&info.queue_depth)
+        {
+            return -libc::EINVAL;
+        }
+
+        if !self.features.contains(info.flags) {
+            return -libc::EINVAL;
+        }
+
+        if info.flags.contains(DeviceFlags::Zoned) && !info.flags.contains(DeviceFlags::UserCopy) {
+            return -libc::EINVAL;
+        }
+
+        if info.dev_id == sys::DevInfo::NEW_DEV_ID {
+            let Some(dev_id) = (0..i32::MAX as u32).find(|id| !self.devices.contains_key(id))
+            else {
+                return -libc::ENOSPC;
+            };
+            info.dev_id = dev_id;
+        } else if self.devices.contains_key(&info.dev_id) {
+            return -libc::EEXIST;
+        }
+
+        // The kernel driver always sets the opcodes encoding it supports
+        info.flags |= self.features & DeviceFlags::CmdIoctlEncode;
+        info.max_io_buf_bytes =
+            round_down_to_page(info.max_io_buf_bytes.min(sys::DevInfo::MAX_BUF_SIZE));
+        info.owner_uid = self.owner_uid;
+        info.owner_gid = self.owner_gid;
+        info.srv_pid = -1;
+        info.state = DeviceState::Dead;
+        info.active = false;
+
+        let res = write_payload(req.buffer_mut(), &sys::DevInfo::from(&info));
+        if res == 0 {
+            self.devices.insert(
+                info.dev_id,
+                MockDevice {
+                    info,
+                    params: None,
+                    recovering: false,
+                    cdev: MockCharDevice::new(&info).ok(),
+                },
+            );
+        }
+        res
+    }
+}
+
+impl Default for MockTransport {
+    /// Mock of a kernel driver supporting all the features
+    fn default() -> Self {
+        Self::new(DeviceFlags::all())
+    }
+}
+
+impl CtrlTransport for MockTransport {
+    unsafe fn submit(&self, user_data: u64, req: &mut CtrlRequest<'_>) -> Result<()> {
+        let mut driver = self.lock_driver();
+        if driver.held_ops.contains(&req.op()) {
+            // SAFETY: the caller guarantees that the buffer outlives the
+            // command, which is executed before completing.
+            let req = unsafe { req.detach() };
+            driver.held.push((user_data, req));
+            return Ok(());
+        }
+
+        let res = driver.handle(req);
+        driver.completions.push((user_data, res));
+        self.shared.completed.notify_all();
+        Ok(())
+    }
+
+    fn reap(&self) -> Result<Vec<(u64, i32)>> {
+        let mut driver = self.lock_driver();
+        while driver.completions.is_empty() {
+            driver = self
+                .shared
+                .completed
+                .wait(driver)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        Ok(mem::take(&mut driver.completions))
+    }
+}
+
+impl MockDevice {
+    fn set_state(&mut self, state: DeviceState) {
+        self.info.state = state;
+        self.info.active = state == DeviceState::Live;
+    }
+
+    // The queues stop, the device doesn't take requests anymore
+    fn stop(&mut self) {
+        self.recovering = false;
+        self.set_state(DeviceState::Dead);
+        if let Some(cdev) = &self.cdev {
+            cdev.abort();
+        }
+    }
+
+    // The queues stop, the requests are held until the user recovery
+    fn quiesce(&mut self) {
+        self.set_state(DeviceState::Quiesced);
+        if let Some(cdev) = &self.cdev {
+            cdev.quiesce();
+        }
+    }
+
+    fn devt(&self) -> DeviceParamDevt {
+        let live = self.info.state != DeviceState::Dead;
+        DeviceParamDevt {
+            char_major: MockTransport::CHAR_MAJOR,
+            char_minor: self.info.dev_id,
+            disk_major: if live { MockTransport::DISK_MAJOR } else { 0 },
+            disk_minor: if live { self.info.dev_id } else { 0 },
+        }
+    }
+
+    fn handle(&mut self, op: CtrlOp, data: u64, buf: &mut [u8]) -> i32 {
+        match op {
+            CtrlOp::GetDevInfo | CtrlOp::GetDevInfo2 => {
+                write_payload(buf, &sys::DevInfo::from(&self.info))
+            }
+            CtrlOp::StartDev => {
+                if self.params.is_none() {
+                    return -libc::EINVAL;
+                }
+                if self.info.state != DeviceState::Dead {
+                    return -libc::EEXIST;
+                }
+                let Some(pid) = i32::try_from(data).ok().filter(|&pid| pid > 0) else {
+                    return -libc::EINVAL;
+                };
+                self.info.srv_pid = pid;
+                self.set_state(DeviceState::Live);
+                0
+            }
+            CtrlOp::StopDev => {
+                self.stop();
+                0
+            }
+            CtrlOp::SetParams => {
+                if self.info.state == DeviceState::Live {
+                    return -libc::EACCES;
+                }
+                let Some(params) = read_payload::<sys::DevParams>(buf) else {
+                    return -libc::EINVAL;
+                };
+                if !params.has_basic() {
+                    return -libc::EINVAL;
+                }
+                let params = DeviceParams::from(params);
+                if !self.valid_params(&params) {
+                    return -libc::EINVAL;
+                }
+                self.params = Some(DeviceParams {
+                    devt: None,
+                    ..params
+                });
+                0
+            }
+            CtrlOp::GetParams => {
+                let mut params = sys::DevParams::from(&self.params.unwrap_or_default());
+                params.set_devt(&self.devt());
+                write_payload(buf, &params)
+            }
+            CtrlOp::GetQueueAffinity => {
+                if data >= u64::from(self.info.nr_hw_queues) {
+                    return -libc::EINVAL;
+                }
+                // The queues are spread over the cpus the process can run on
+                let cpus = allowed_cpus();
+                // SAFETY: all-zero byte-pattern represents a valid libc::cpu_set_t
+                let mut cpu_set: libc::cpu_set_t = unsafe { mem::zeroed() };
+                if let Some(&cpu) = cpus.get(data as usize % cpus.len().max(1)) {
+                    // SAFETY: the cpu was taken from a set of the same size.
+                    unsafe { libc::CPU_SET(cpu, &mut cpu_set) };
+                }
+                write_payload(buf, &cpu_set)
+            }
+            CtrlOp::StartUserRecovery => {
+                if !self.info.flags.contains(DeviceFlags::UserRecovery) {
+                    return -libc::EINVAL;
+                }
+                if self.info.state != DeviceState::Quiesced {
+                    return -libc::EBUSY;
+                }
+                self.recovering = true;
+                if let Some(cdev) = &self.cdev {
+                    cdev.resume();
+                }
+                0
+            }
+            CtrlOp::EndUserRecovery => {
+                if !self.recovering || self.info.state != DeviceState::Quiesced {
+                    return -libc::EBUSY;
+                }
+                let Ok(pid) = i32::try_from(data) else {
+                    return -libc::EINVAL;
+                };
+                self.recovering = false;
+                self.info.srv_pid = pid;
+                self.set_state(DeviceState::Live);
+                0
+            }
+            CtrlOp::UpdateSize => {
+                if !self.info.flags.contains(DeviceFlags::UpdateSize) {
+                    return -libc::EOPNOTSUPP;
+                }
+                if self.info.state != DeviceState::Live {
+                    return -libc::EINVAL;
+                }
+                if let Some(params) = self.params.as_mut() {
+                    params.dev_sectors = data;
+                }
+                0
+            }
+            CtrlOp::QuiesceDev => {
+                if !self.info.flags.contains(DeviceFlags::Quiesce) {
+                    return -libc::EOPNOTSUPP;
+                }
+                if self.info.state != DeviceState::Live {
+                    return -libc::EINVAL;
+                }
+                self.quiesce();
+                0
+            }
+            CtrlOp::AddDev | CtrlOp::DelDev | CtrlOp::GetFeatures => {
+                unreachable!("not a device command")
+            }
+        }
+    }
+
+    // The checks done by the kernel driver on SetParams
+    fn valid_params(&self, p: &DeviceParams) -> bool {
+        let page_shift = queue::page_size().trailing_zeros();
+        let lbs_shift = u32::from(p.logical_bs_shift);
+
+        if !(9..=page_shift).contains(&lbs_shift) || p.logical_bs_shift > p.physical_bs_shift {
+            return false;
+        }
+
+        if p.max_sectors > self.info.max_io_buf_bytes >> 9 {
+            return false;
+        }
+
+        if let Some(discard) = &p.discard {
+            if discard.discard_granularity == 0
+                || (discard.max_discard_sectors != 0 && discard.max_discard_segments != 1)
+            {
+                return false;
+            }
+        }
+
+        if self.info.flags.contains(DeviceFlags::Zoned)
+            && (p.zoned.is_none() || p.chunk_sectors == 0)
+        {
+            return false;
+        }
+
+        true
+    }
+}
+
+// The cpus in the affinity of the calling thread
+fn allowed_cpus() -> Vec<usize> {
+    // SAFETY: all-zero byte-pattern represents a valid libc::cpu_set_t
+    let mut cpu_set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    // SAFETY: `cpu_set` is valid for writes, and pid 0 means the calling thread.
+    let ret =
+        unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut cpu_set) };
+    if ret < 0 {
+        return Vec::new();
+    }
+
+    (0..libc::CPU_SETSIZE as usize)
+        // SAFETY: the cpu is lower than the set size.
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &cpu_set) })
+        .collect()
+}
+
+fn round_down_to_page(len: u32) -> u32 {
+    let page_size = queue::page_size() as u32;
+    len / page_size * page_size
+}
+
+// Command payloads, plain data structures valid for any bit pattern
+trait Payload: Copy {}
+
+impl Payload for sys::DevInfo {}
+impl Payload for sys::DevParams {}
+impl Payload for sys::Features {}
+impl Payload for libc::cpu_set_t {}
+
+fn read_payload<T: Payload>(buf: &[u8]) -> Option<T> {
+    if buf.len() < mem::size_of::<T>() {
+        return None;
+    }
+    // SAFETY: `buf` holds at least one `T`, valid for any bit pattern.
+    Some(unsafe { ptr::read_unaligned(buf.as_ptr().cast::<T>()) })
+}
+
+// Returns the command result, the buffer must hold the whole payload
+fn write_payload<T: Payload>(buf: &mut [u8], payload: &T) -> i32 {
+    if buf.len() < mem::size_of::<T>() {
+        return -libc::EINVAL;
+    }
+    // SAFETY: `buf` is valid for writes of one `T`.
+    unsafe { ptr::write_unaligned(buf.as_mut_ptr().cast::<T>(), *payload) };
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{CmdEncoding, DeviceOptions, UblkCtrl, UblkCtrlAsync};
+    use crate::Error;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+
+    const PID: u64 = 1234;
+
+    fn params() -> DeviceParams {
+        DeviceParams {
+            logical_bs_shift: 9,
+            physical_bs_shift: 12,
+            io_opt_shift: 12,
+            io_min_shift: 9,
+            max_sectors: 128,
+            dev_sectors: 2048,
+            ..DeviceParams::default()
+        }
+    }
+
+    fn add_started(ctrl: &mut UblkCtrl, flags: DeviceFlags) -> u32 {
+        let info = ctrl.add_device(&DeviceOptions::new().flags(flags)).unwrap();
+        ctrl.set_device_parameters(info.dev_id, &params()).unwrap();
+        ctrl.start_device(info.dev_id, PID).unwrap();
+        info.dev_id
+    }
+
+    #[test]
+    fn lifecycle() {
+        let mut ctrl = UblkCtrl::with_transport(MockTransport::default());
+
+        let info = ctrl.add_device(&DeviceOptions::new()).unwrap();
+        assert_eq!(info.dev_id, 0);
+        assert_eq!(info.state, DeviceState::Dead);
+
+        let dev_id = info.dev_id;
+        ctrl.set_device_parameters(dev_id, &params()).unwrap();
+        let params = ctrl.get_device_parameters(dev_id).unwrap();
+        assert_eq!(params.dev_sectors, 2048);
+        assert_eq!(params.devt.and_then(|devt| devt.disk_devt()), None);
+
+        ctrl.start_device(dev_id, PID).unwrap();
+        let info = ctrl.get_device_info(dev_id).unwrap();
+        assert_eq!(info.state, DeviceState::Live);
+        assert_eq!(info.srv_pid, PID as i32);
+        let params = ctrl.get_device_parameters(dev_id).unwrap();
+        assert!(params.devt.and_then(|devt| devt.disk_devt()).is_some());
+
+        ctrl.stop_device(dev_id).unwrap();
+        let info = ctrl.get_device_info(dev_id).unwrap();
+        assert_eq!(info.state, DeviceState::Dead);
+
+        ctrl.delete_device(dev_id).unwrap();
+        assert!(matches!(
+            ctrl.get_device_info(dev_id),
+            Err(Error::DeviceNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn unprivileged_lifecycle() {
+        let mut ctrl = UblkCtrl::with_transport(MockTransport::default());
+
+        let dev_id = add_started(&mut ctrl, DeviceFlags::Unprivileged);
+        ctrl.stop_device(dev_id).unwrap();
+        ctrl.delete_device(dev_id).unwrap();
+    }
+
+    #[test]
+    fn start_without_params() {
+        let mut ctrl = UblkCtrl::with_transport(MockTransport::default());
+
+        let info = ctrl.add_device(&DeviceOptions::new()).unwrap();
+        assert!(ctrl.start_device(info.dev_id, PID).is_err());
+    }
+
+    #[test]
+    fn device_not_found() {
+        let mut ctrl = UblkCtrl::with_transport(MockTransport::default());
+
+        let err = ctrl.start_device(3, PID).unwrap_err();
+        assert!(matches!(err, Error::DeviceNotFound { dev_id: 3, .. }));
+        assert_eq!(err.raw_os_error(), Some(libc::ENODEV));
+    }
+
+    #[test]
+    fn already_exists() {
+        let mut ctrl = UblkCtrl::with_transport(MockTransport::default());
+
+        ctrl.add_device(&DeviceOptions::new().device_id(5)).unwrap();
+        let err = ctrl
+            .add_device(&DeviceOptions::new().device_id(5))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::AlreadyExists {
+                op: CtrlOp::AddDev,
+                ..
+            }
+        ));
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    }
+
+    #[test]
+    fn device_busy() {
+        let mut ctrl = UblkCtrl::with_transport(MockTransport::default());
+
+        let dev_id = add_started(&mut ctrl, DeviceFlags::UserRecovery);
+        let err = ctrl.start_user_recovery(dev_id).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::DeviceBusy {
+                op: CtrlOp::StartUserRecovery,
+                ..
+            }
+        ));
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+    }
+
+    #[test]
+    fn permission_denied() {
+        let mut ctrl = UblkCtrl::with_transport(MockTransport::default());
+
+        // The parameters of a live device can't be changed
+        let dev_id = add_started(&mut ctrl, DeviceFlags::empty());
+        let err = ctrl.set_device_parameters(dev_id, &params()).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::PermissionDenied {
+                op: CtrlOp::SetParams,
+                errno: libc::EACCES,
+                ..
+            }
+        ));
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+    }
+
+    // Fails all the device commands with `errno`
+    struct FailingTransport(i32, Mutex<Vec<(u64, i32)>>);
+
+    impl CtrlTransport for FailingTransport {
+        unsafe fn submit(&self, user_data: u64, req: &mut CtrlRequest<'_>) -> Result<()> {
+            let res = match req.op() {
+                CtrlOp::GetFeatures => 0,
+                _ => -self.0,
+            };
+            self.1.lock().unwrap().push((user_data, res));
+            Ok(())
+        }
+
+        fn reap(&self) -> Result<Vec<(u64, i32)>> {
+            Ok(mem::take(&mut *self.1.lock().unwrap()))
+        }
+    }
+
+    #[test]
+    fn errno_mapping() {
+        for errno in [
+            libc::ENODEV,
+            libc::EBUSY,
+            libc::EEXIST,
+            libc::EPERM,
+            libc::EACCES,
+            libc::EOPNOTSUPP,
+            libc::EIO,
+        ] {
+            let mut ctrl = UblkCtrl::with_transport(FailingTransport(errno, Mutex::default()));
+            let err = ctrl.stop_device(0).unwrap_err();
+
+            let mapped = match err {
+                Error::DeviceNotFound { .. } => libc::ENODEV,
+                Error::DeviceBusy { .. } => libc::EBUSY,
+                Error::AlreadyExists { .. } => libc::EEXIST,
+                Error::PermissionDenied { errno, .. } => errno,
+                Error::OperationNotSupported { .. } => libc::EOPNOTSUPP,
+                Error::Ctrl { ref source, .. } => source.raw_os_error().unwrap(),
+                ref err => panic!("unexpected {err:?}"),
+            };
+            assert_eq!(mapped, errno);
+            assert_eq!(err.raw_os_error(), Some(errno));
+        }
+    }
+
+    #[test]
+    fn feature_negotiation() {
+        let features = DeviceFlags::CmdIoctlEncode | DeviceFlags::UserCopy;
+        let mut ctrl = UblkCtrl::with_transport(MockTransport::new(features));
+        assert_eq!(ctrl.get_features().unwrap(), features);
+        assert_eq!(ctrl.cmd_encoding(), CmdEncoding::Ioctl);
+
+        let options = DeviceOptions::new().flags(DeviceFlags::UserCopy | DeviceFlags::Quiesce);
+        let err = ctrl.add_device(&options).unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFeatures(flags) if flags == DeviceFlags::Quiesce));
+
+        let info = ctrl.add_device(&options.negotiate_features(true)).unwrap();
+        assert_eq!(info.flags, features);
+    }
+
+    #[test]
+    fn legacy_encoding() {
+        let ctrl = UblkCtrl::with_transport(MockTransport::new(DeviceFlags::empty()));
+        assert_eq!(ctrl.cmd_encoding(), CmdEncoding::Legacy);
+    }
+
+    #[test]
+    fn update_size() {
+        let mut ctrl = UblkCtrl::with_transport(MockTransport::default());
+
+        let info = ctrl
+            .add_device(&DeviceOptions::new().flags(DeviceFlags::UpdateSize))
+            .unwrap();
+        ctrl.set_device_parameters(info.dev_id, &params()).unwrap();
+        assert!(ctrl.update_size(info.dev_id, 4096).is_err());
+
+        ctrl.start_device(info.dev_id, PID).unwrap();
+        ctrl.update_size(info.dev_id, 4096).unwrap();
+        let params = ctrl.get_device_parameters(info.dev_id).unwrap();
+        assert_eq!(params.dev_sectors, 4096);
+    }
+
+    #[test]
+    fn update_size_unsupported() {
+        let mut ctrl = UblkCtrl::with_transport(MockTransport::default());
+
+        let dev_id = add_started(&mut ctrl, DeviceFlags::empty());
+        let err = ctrl.update_size(dev_id, 4096).unwrap_err();
+        assert!(matches!(err, Error::OperationNotSupported { .. }));
+        let params = ctrl.get_device_parameters(dev_id).unwrap();
+        assert_eq!(params.dev_sectors, 2048);
+    }
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = pin!(fut);
+        loop {
+            if let Poll::Ready(res) = fut.as_mut().poll(&mut cx) {
+                return res;
+            }
+            thread::park();
+        }
+    }
+
+    // Polls both futures until they both resolve
+    async fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+        let (mut a, mut b) = (pin!(a), pin!(b));
+        let (mut a_res, mut b_res) = (None, None);
+        std::future::poll_fn(|cx| {
+            if a_res.is_none() {
+                if let Poll::Ready(res) = a.as_mut().poll(cx) {
+                    a_res = Some(res);
+                }
+            }
+            if b_res.is_none() {
+                if let Poll::Ready(res) = b.as_mut().poll(cx) {
+                    b_res = Some(res);
+                }
+            }
+            if a_res.is_some() && b_res.is_some() {
+                Poll::Ready((a_res.take().unwrap(), b_res.take().unwrap()))
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    #[test]
+    fn async_lifecycle() {
+        for flags in [DeviceFlags::empty(), DeviceFlags::Unprivileged] {
+            let ctrl = UblkCtrlAsync::with_transport(MockTransport::default()).unwrap();
+
+            let info = block_on(ctrl.add_device(&DeviceOptions::new().flags(flags))).unwrap();
+            let dev_id = info.dev_id;
+            block_on(ctrl.set_device_parameters(dev_id, &params())).unwrap();
+            block_on(ctrl.start_device(dev_id, PID)).unwrap();
+
+            let info = block_on(ctrl.get_device_info(dev_id)).unwrap();
+            assert_eq!(info.state, DeviceState::Live);
+            let params = block_on(ctrl.get_device_parameters(dev_id)).unwrap();
+            assert_eq!(params.dev_sectors, 2048);
+
+            block_on(ctrl.stop_device(dev_id)).unwrap();
+            block_on(ctrl.delete_device(dev_id)).unwrap();
+            assert!(matches!(
+                block_on(ctrl.get_device_info(dev_id)),
+                Err(Error::DeviceNotFound { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn async_concurrent_commands() {
+        let mock = MockTransport::default();
+        let ctrl = UblkCtrlAsync::with_transport(mock.clone()).unwrap();
+
+        let info = block_on(ctrl.add_device(&DeviceOptions::new())).unwrap();
+        let dev_id = info.dev_id;
+        block_on(ctrl.set_device_parameters(dev_id, &params())).unwrap();
+        block_on(ctrl.start_device(dev_id, PID)).unwrap();
+
+        // The device info completes while the StopDev is still in flight
+        mock.hold(CtrlOp::StopDev);
+        let (stopped, info) = block_on(join(ctrl.stop_device(dev_id), async {
+            let info = ctrl.get_device_info(dev_id).await;
+            assert_eq!(mock.nr_held(), 1);
+            mock.release(CtrlOp::StopDev);
+            info
+        }));
+        assert_eq!(info.unwrap().state, DeviceState::Live);
+        stopped.unwrap();
+
+        let info = block_on(ctrl.get_device_info(dev_id)).unwrap();
+        assert_eq!(info.state, DeviceState::Dead);
+    }
+}
@@ -1,76 +1,141 @@
 // SPDX-License-Identifier: MIT
 
+mod async_ctrl;
+#[cfg(feature = "serde")]
+mod flags_serde;
+#[cfg(any(test, feature = "mock"))]
+mod mock;
 mod sys;
+mod transport;
 
-use crate::error::Result;
+pub use async_ctrl::UblkCtrlAsync;
+#[cfg(any(test, feature = "mock"))]
+pub use mock::MockTransport;
+pub use transport::{CtrlRequest, CtrlTransport, UringTransport};
+
+use crate::error::{Error, Result};
+use crate::queue::UblkQueue;
 use bitflags::bitflags;
-use io_uring::{cqueue, squeue, IoUring};
-use std::fs::OpenOptions;
-use std::mem;
-use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+use std::time::Duration;
+use std::{io, mem};
 
 /// Control object
+///
+/// The commands are sent through a [`CtrlTransport`], by default to the kernel
+/// driver with `io_uring`, see [`with_transport()`](Self::with_transport).
 pub struct UblkCtrl {
-    ring: IoUring<squeue::Entry128, cqueue::Entry32>,
-    uniq: u64,
-    _ctrl_dev: OwnedFd,
+    transport: Box<dyn CtrlTransport>,
+    encoding: CmdEncoding,
+    features: DeviceFlags,
+    // whether the known devices are unprivileged, see `needs_dev_path()`
+    unprivileged: HashMap<u32, bool>,
 }
 
 impl UblkCtrl {
     /// ublk control device path
     pub const CTRL_DEV_PATH: &'static str = "/dev/ublk-control";
 
+    /// sysfs class of the ublk char devices
+    pub const CHAR_DEV_CLASS_PATH: &'static str = "/sys/class/ublk-char-device";
+
     /// ubcltrl constructor
     /// # Errors
     ///
     pub fn new() -> Result<Self> {
-        let ring = IoUring::generic_builder().build(32)?;
+        Ok(Self::with_transport(UringTransport::new()?))
+    }
 
-        let ctrl_dev = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(Self::CTRL_DEV_PATH)?;
+    /// Control object sending the commands through `transport`
+    #[must_use]
+    pub fn with_transport<T: CtrlTransport + 'static>(transport: T) -> Self {
+        let mut ctrl = Self {
+            transport: Box::new(transport),
+            encoding: CmdEncoding::Legacy,
+            features: DeviceFlags::empty(),
+            unprivileged: HashMap::new(),
+        };
 
-        ring.submitter().register_files(&[ctrl_dev.as_raw_fd()])?;
+        // Kernels without `GetFeatures` only support legacy opcodes
+        ctrl.features = ctrl.get_features().unwrap_or(DeviceFlags::empty());
+        if ctrl.features.contains(DeviceFlags::CmdIoctlEncode) {
+            ctrl.encoding = CmdEncoding::Ioctl;
+        }
 
-        let ctrl = Self {
-            ring,
-            uniq: 0,
-            _ctrl_dev: ctrl_dev.into(),
-        };
-        Ok(ctrl)
+        ctrl
+    }
+
+    /// Returns the encoding of the control commands opcodes in use
+    #[must_use]
+    pub const fn cmd_encoding(&self) -> CmdEncoding {
+        self.encoding
     }
 
     /// Add new device
+    ///
+    /// If feature negotiation is enabled (see [`DeviceOptions::negotiate_features()`])
+    /// the requested flags not supported by the kernel driver are dropped, the
+    /// returned [`DeviceInfo`] contains the flags actually enabled.
     /// # Errors
     ///
+    /// Returns [`Error::UnsupportedFeatures`] with the rejected flags, if the kernel
+    /// driver refuses to add the device because of them, or [`Error::AlreadyExists`]
+    /// if the requested device id is in use.
     pub fn add_device(&mut self, options: &DeviceOptions) -> Result<DeviceInfo> {
-        self.uniq += 1;
+        let mut options = *options;
+        if options.negotiate_features {
+            options.flags &= self.get_features()?;
+        }
 
-        // if after cast `dev_id` < 0, it means requesting a new id
-        let mut info = sys::DevInfo::new()
-            .dev_id(options.dev_id)
-            .max_io_buf_bytes(options.max_io_buf_bytes)
-            .nr_hw_queues(options.nr_hw_queues)
-            .queue_depth(options.queue_depth)
-            .flags(options.flags.bits());
+        let mut info: sys::DevInfo = (&options).into();
 
         // The kernel driver fails if info.dev_id != cmd.dev_id
-        sys::CtrlCmd::new(sys::CtrlOp::AddDev, options.dev_id)
-            .buffer(&mut info)
-            .submit_and_wait(self.uniq, &mut self.ring)?;
+        let res = self.execute(
+            sys::CtrlCmd::new(CtrlOp::AddDev, options.dev_id, self.encoding).buffer(&mut info),
+        );
 
-        Ok(info.into())
+        if let Err(err) = res {
+            return Err(self.unsupported_features(options.flags).unwrap_or(err));
+        }
+
+        let info: DeviceInfo = info.into();
+        self.unprivileged
+            .insert(info.dev_id, info.flags.contains(DeviceFlags::Unprivileged));
+
+        Ok(info)
+    }
+
+    /// Get the features supported by the kernel driver
+    /// # Errors
+    ///
+    pub fn get_features(&mut self) -> Result<DeviceFlags> {
+        let mut features: sys::Features = 0;
+
+        self.execute(
+            sys::CtrlCmd::new(CtrlOp::GetFeatures, sys::DevInfo::NEW_DEV_ID, self.encoding)
+                .buffer(&mut features),
+        )?;
+
+        Ok(DeviceFlags::from_bits_truncate(features))
+    }
+
+    // Checks if the kernel driver supports the requested flags,
+    // returns an error with the rejected ones (if any)
+    fn unsupported_features(&mut self, flags: DeviceFlags) -> Option<Error> {
+        let supported = self.get_features().ok()?;
+        let rejected = flags - supported;
+        (!rejected.is_empty()).then_some(Error::UnsupportedFeatures(rejected))
     }
 
     /// Delete a device
     /// # Errors
     ///
     pub fn delete_device(&mut self, dev_id: u32) -> Result<()> {
-        self.uniq += 1;
-
-        sys::CtrlCmd::new(sys::CtrlOp::DelDev, dev_id)
-            .submit_and_wait(self.uniq, &mut self.ring)?;
+        self.device_cmd::<()>(CtrlOp::DelDev, dev_id, 0, None)?;
+        self.unprivileged.remove(&dev_id);
 
         Ok(())
     }
@@ -80,25 +145,19 @@ impl UblkCtrl {
     /// 1) fork a daemon for handling IO command from driver
     ///
     /// 2) wait for the device becoming ready: the daemon should submit
-    /// sqes to /dev/ublkcN, just like usb's urb usage, each request needs
-    /// one sqe. If one IO request comes to kernel driver of /dev/ublkbN,
-    /// the sqe for this request is completed, and the daemon gets notified.
-    /// When every io request of driver gets its own sqe queued, we think
-    /// /dev/ublkbN is ready to start
+    ///    sqes to /dev/ublkcN, just like usb's urb usage, each request needs
+    ///    one sqe. If one IO request comes to kernel driver of /dev/ublkbN,
+    ///    the sqe for this request is completed, and the daemon gets notified.
+    ///    When every io request of driver gets its own sqe queued, we think
+    ///    /dev/ublkbN is ready to start
     ///
     /// 3) in current process context, sent `StartDev` command to
-    /// /dev/ublk-control with device id, which will cause ublk driver to
-    /// expose /dev/ublkbN
+    ///    /dev/ublk-control with device id, which will cause ublk driver to
+    ///    expose /dev/ublkbN
     /// # Errors
     ///
     pub fn start_device(&mut self, dev_id: u32, pid: u64) -> Result<()> {
-        self.uniq += 1;
-
-        sys::CtrlCmd::new(sys::CtrlOp::StartDev, dev_id)
-            .data(pid)
-            .submit_and_wait(self.uniq, &mut self.ring)?;
-
-        Ok(())
+        self.device_cmd::<()>(CtrlOp::StartDev, dev_id, pid, None)
     }
 
     ///  Stop the ublksrv device:
@@ -106,20 +165,15 @@ impl UblkCtrl {
     ///  1) send `StopDev` command to /dev/ublk-control with device id provided
     ///
     ///  2) ublk driver gets this command, freeze /dev/ublkbN, then complete all
-    ///  pending seq, meantime tell the daemon via cqe->res to not submit sqe
-    ///  any more, since we are being closed. Also delete /dev/ublkbN.
+    ///     pending seq, meantime tell the daemon via cqe->res to not submit sqe
+    ///     any more, since we are being closed. Also delete /dev/ublkbN.
     ///
     ///  3) the ublk daemon figures out that all sqes are completed, and free,
-    ///  then close /dev/ublkcN and exit itself.
+    ///     then close /dev/ublkcN and exit itself.
     /// # Errors
     ///
     pub fn stop_device(&mut self, dev_id: u32) -> Result<()> {
-        self.uniq += 1;
-
-        sys::CtrlCmd::new(sys::CtrlOp::StopDev, dev_id)
-            .submit_and_wait(self.uniq, &mut self.ring)?;
-
-        Ok(())
+        self.device_cmd::<()>(CtrlOp::StopDev, dev_id, 0, None)
     }
 
     /// Set the device parameters
@@ -127,47 +181,47 @@ impl UblkCtrl {
     /// # Errors
     ///
     pub fn set_device_parameters(&mut self, dev_id: u32, params: &DeviceParams) -> Result<()> {
-        self.uniq += 1;
-
         let mut params: sys::DevParams = params.into();
 
-        sys::CtrlCmd::new(sys::CtrlOp::SetParams, dev_id)
-            .buffer(&mut params)
-            .submit_and_wait(self.uniq, &mut self.ring)?;
-
-        Ok(())
+        self.device_cmd(CtrlOp::SetParams, dev_id, 0, Some(&mut params))
     }
 
     /// Get the device parameters
     /// # Errors
     ///
     pub fn get_device_parameters(&mut self, dev_id: u32) -> Result<DeviceParams> {
-        self.uniq += 1;
-
         let mut params = sys::DevParams::empty();
 
-        sys::CtrlCmd::new(sys::CtrlOp::GetParams, dev_id)
-            .buffer(&mut params)
-            .submit_and_wait(self.uniq, &mut self.ring)?;
+        self.device_cmd(CtrlOp::GetParams, dev_id, 0, Some(&mut params))?;
 
         Ok(params.into())
     }
 
+    /// Change the size of a live device to `sectors`
+    ///
+    /// The device must be created with [`DeviceFlags::UpdateSize`], the backing
+    /// storage must be already resized since the new capacity is visible right away.
+    /// # Errors
+    ///
+    pub fn update_size(&mut self, dev_id: u32, sectors: u64) -> Result<()> {
+        self.device_cmd::<()>(CtrlOp::UpdateSize, dev_id, sectors, None)
+    }
+
     /// Get device's queue affinity
     ///
     /// This is only used for setting up queue pthread daemons
     /// # Errors
     ///
     pub fn get_queue_affinity(&mut self, dev_id: u32, queue: u16) -> Result<libc::cpu_set_t> {
-        self.uniq += 1;
-
         // SAFETY: all-zero byte-pattern represents a valid libc::cpu_set_t
         let mut cpu_set: libc::cpu_set_t = unsafe { mem::zeroed() };
 
-        sys::CtrlCmd::new(sys::CtrlOp::GetQueueAffinity, dev_id)
-            .buffer(&mut cpu_set)
-            .data(u64::from(queue))
-            .submit_and_wait(self.uniq, &mut self.ring)?;
+        self.device_cmd(
+            CtrlOp::GetQueueAffinity,
+            dev_id,
+            u64::from(queue),
+            Some(&mut cpu_set),
+        )?;
 
         Ok(cpu_set)
     }
@@ -182,41 +236,382 @@ impl UblkCtrl {
         dev_id: u32,
         nr_queues: u16,
     ) -> Result<Vec<libc::cpu_set_t>> {
-        let mut set: Vec<libc::cpu_set_t> = Vec::with_capacity(nr_queues as usize);
+        // SAFETY: all-zero byte-pattern represents a valid libc::cpu_set_t
+        let mut set: Vec<libc::cpu_set_t> = vec![unsafe { mem::zeroed() }; nr_queues as usize];
+
+        if self.needs_dev_path(CtrlOp::GetQueueAffinity, dev_id)? {
+            let mut bufs: Vec<_> = set
+                .iter()
+                .map(|cpu_set| sys::DevPathBuffer::new(dev_id, Some(cpu_set)))
+                .collect();
+
+            let cmds: Vec<_> = bufs
+                .iter_mut()
+                .zip(0..nr_queues)
+                .map(|(buf, queue)| {
+                    sys::CtrlCmd::new(CtrlOp::GetQueueAffinity, dev_id, self.encoding)
+                        .dev_path_buffer(buf)
+                        .data(u64::from(queue))
+                })
+                .collect();
+
+            for res in self.execute_batch(cmds)? {
+                res?;
+            }
+
+            return Ok(bufs
+                .iter()
+                .filter_map(sys::DevPathBuffer::payload)
+                .collect());
+        }
 
-        for queue in 0..nr_queues {
-            let cpu_set = self.get_queue_affinity(dev_id, queue)?;
-            set.push(cpu_set);
+        let cmds: Vec<_> = set
+            .iter_mut()
+            .zip(0..nr_queues)
+            .map(|(cpu_set, queue)| {
+                sys::CtrlCmd::new(CtrlOp::GetQueueAffinity, dev_id, self.encoding)
+                    .buffer(cpu_set)
+                    .data(u64::from(queue))
+            })
+            .collect();
+
+        for res in self.execute_batch(cmds)? {
+            res?;
         }
 
         Ok(set)
     }
 
+    /// Start the user recovery of a quiesced device
+    ///
+    /// After the server of a device created with [`DeviceFlags::UserRecovery`] dies,
+    /// the device is quiesced until a new server recovers it:
+    ///
+    /// 1) send `StartUserRecovery` command to /dev/ublk-control, the device must be
+    ///    quiesced, i.e., all the queues of the previous server are gone
+    ///
+    /// 2) the new server opens /dev/ublkcN and submits the fetch commands of every queue
+    ///
+    /// 3) send `EndUserRecovery` with the new server PID, see
+    ///    [`end_user_recovery()`](Self::end_user_recovery)
+    /// # Errors
+    ///
+    pub fn start_user_recovery(&mut self, dev_id: u32) -> Result<()> {
+        self.device_cmd::<()>(CtrlOp::StartUserRecovery, dev_id, 0, None)
+    }
+
+    /// End the user recovery of a device
+    ///
+    /// The kernel driver waits for every queue to be ready before
+    /// completing this command, then the device becomes live again.
+    /// # Errors
+    ///
+    pub fn end_user_recovery(&mut self, dev_id: u32, pid: u64) -> Result<()> {
+        self.device_cmd::<()>(CtrlOp::EndUserRecovery, dev_id, pid, None)
+    }
+
+    /// Quiesce a live device
+    ///
+    /// The new I/O is held by the kernel driver, and the in-flight I/O is drained
+    /// waiting at most `timeout` (forever if `None`). Then the fetch commands of
+    /// every queue are aborted, once the server closes /dev/ublkcN the device is
+    /// quiesced. The device must be created with [`DeviceFlags::Quiesce`].
+    ///
+    /// To resume the device a server must reattach to it, as when recovering it,
+    /// see [`start_user_recovery()`](Self::start_user_recovery).
+    /// # Errors
+    ///
+    /// Fails with `ETIMEDOUT` if the in-flight I/O is not completed within `timeout`.
+    pub fn quiesce_device(&mut self, dev_id: u32, timeout: Option<Duration>) -> Result<()> {
+        self.device_cmd::<()>(
+            CtrlOp::QuiesceDev,
+            dev_id,
+            quiesce_timeout_ms(timeout),
+            None,
+        )
+    }
+
+    /// List the ids of the existing devices, in increasing order
+    ///
+    /// The devices are discovered from their char devices, both in
+    /// [`CHAR_DEV_CLASS_PATH`](Self::CHAR_DEV_CLASS_PATH) and in /dev, so no
+    /// command is sent to the kernel driver.
+    /// # Errors
+    ///
+    /// Fails if /dev cannot be read.
+    pub fn list_devices() -> Result<Vec<u32>> {
+        let cdev_prefix = Path::new(UblkQueue::CDEV_PATH_PREFIX);
+        let dev_dir = cdev_prefix.parent().unwrap_or(Path::new("/dev"));
+        let name_prefix = cdev_prefix
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or_default();
+
+        let mut dev_ids = BTreeSet::new();
+        for dir in [Path::new(Self::CHAR_DEV_CLASS_PATH), dev_dir] {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                // The class doesn't exist until the module is loaded
+                Err(err) if err.kind() == io::ErrorKind::NotFound && dir != dev_dir => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            for entry in entries {
+                let name = entry?.file_name();
+                if let Some(dev_id) = name
+                    .to_str()
+                    .and_then(|name| name.strip_prefix(name_prefix))
+                    .and_then(|dev_id| dev_id.parse().ok())
+                {
+                    dev_ids.insert(dev_id);
+                }
+            }
+        }
+
+        Ok(dev_ids.into_iter().collect())
+    }
+
     /// Get the device information
+    ///
+    /// If the kernel driver supports unprivileged devices, it uses the `GetDevInfo2`
+    /// command, which is allowed to the device's owner.
     /// # Errors
     ///
     pub fn get_device_info(&mut self, dev_id: u32) -> Result<DeviceInfo> {
-        self.uniq += 1;
-
         let mut info = sys::DevInfo::new();
 
-        sys::CtrlCmd::new(sys::CtrlOp::GetDevInfo, dev_id)
-            .buffer(&mut info)
-            .submit_and_wait(self.uniq, &mut self.ring)?;
+        let op = self.get_dev_info_op();
+        self.device_cmd(op, dev_id, 0, Some(&mut info))?;
+
+        let info: DeviceInfo = info.into();
+        if op == CtrlOp::GetDevInfo2 {
+            self.unprivileged
+                .insert(dev_id, info.flags.contains(DeviceFlags::Unprivileged));
+        }
+
+        Ok(info)
+    }
+
+    /// Get the information of several devices at once
+    ///
+    /// All the commands are submitted together, returns each device's
+    /// information (or error) in the same order as `dev_ids`.
+    /// # Errors
+    ///
+    /// Fails only if the commands cannot be submitted.
+    pub fn get_devices_info(&mut self, dev_ids: &[u32]) -> Result<Vec<Result<DeviceInfo>>> {
+        if self.get_dev_info_op() == CtrlOp::GetDevInfo2 {
+            let mut bufs: Vec<_> = dev_ids
+                .iter()
+                .map(|&dev_id| sys::DevPathBuffer::new(dev_id, Some(&sys::DevInfo::new())))
+                .collect();
+
+            let cmds: Vec<_> = bufs
+                .iter_mut()
+                .zip(dev_ids)
+                .map(|(buf, &dev_id)| {
+                    sys::CtrlCmd::new(CtrlOp::GetDevInfo2, dev_id, self.encoding)
+                        .dev_path_buffer(buf)
+                })
+                .collect();
+
+            let results = self.execute_batch(cmds)?;
+
+            return Ok(results
+                .into_iter()
+                .zip(bufs)
+                .map(|(res, buf)| {
+                    res?;
+                    let info: DeviceInfo = buf.payload().unwrap_or_default().into();
+                    self.unprivileged
+                        .insert(info.dev_id, info.flags.contains(DeviceFlags::Unprivileged));
+                    Ok(info)
+                })
+                .collect());
+        }
+
+        let mut infos = vec![sys::DevInfo::new(); dev_ids.len()];
+
+        let cmds: Vec<_> = infos
+            .iter_mut()
+            .zip(dev_ids)
+            .map(|(info, &dev_id)| {
+                sys::CtrlCmd::new(CtrlOp::GetDevInfo, dev_id, self.encoding).buffer(info)
+            })
+            .collect();
+
+        let results = self.execute_batch(cmds)?;
+
+        Ok(results
+            .into_iter()
+            .zip(infos)
+            .map(|(res, info)| res.map(|_| info.into()))
+            .collect())
+    }
+
+    /// Delete several devices at once
+    ///
+    /// All the commands are submitted together, returns each deletion's
+    /// result in the same order as `dev_ids`.
+    /// # Errors
+    ///
+    /// Fails only if the commands cannot be submitted.
+    pub fn delete_devices(&mut self, dev_ids: &[u32]) -> Result<Vec<Result<()>>> {
+        // The unknown devices must be looked up to know which ones are unprivileged
+        if self.features.contains(DeviceFlags::Unprivileged) {
+            let unknown: Vec<u32> = dev_ids
+                .iter()
+                .copied()
+                .filter(|dev_id| !self.unprivileged.contains_key(dev_id))
+                .collect();
+            self.get_devices_info(&unknown)?;
+        }
+
+        let mut bufs: Vec<Option<sys::DevPathBuffer<()>>> = dev_ids
+            .iter()
+            .map(|&dev_id| {
+                let unprivileged = self.unprivileged.get(&dev_id).copied();
+                unprivileged
+                    .unwrap_or(false)
+                    .then(|| sys::DevPathBuffer::new(dev_id, None))
+            })
+            .collect();
+
+        let cmds: Vec<_> = bufs
+            .iter_mut()
+            .zip(dev_ids)
+            .map(|(buf, &dev_id)| {
+                let cmd = sys::CtrlCmd::new(CtrlOp::DelDev, dev_id, self.encoding);
+                match buf {
+                    Some(buf) => cmd.dev_path_buffer(buf),
+                    None => cmd,
+                }
+            })
+            .collect();
+
+        let results = self.execute_batch(cmds)?;
+
+        for (res, dev_id) in results.iter().zip(dev_ids) {
+            if res.is_ok() {
+                self.unprivileged.remove(dev_id);
+            }
+        }
+
+        Ok(results)
+    }
+
+    // The control device is missing if the ublk module is not loaded
+    fn open_ctrl_dev() -> Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(Self::CTRL_DEV_PATH)
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => Error::ModuleNotLoaded,
+                _ => err.into(),
+            })
+    }
+
+    // Sends a command and waits for its completion
+    fn execute(&mut self, cmd: sys::CtrlCmd<'_>) -> Result<()> {
+        let mut results = self.execute_batch(vec![cmd])?;
+        results.pop().expect("ctrl command result")
+    }
+
+    // Sends several commands at once, returns each command's result
+    // in the same order
+    fn execute_batch(&mut self, cmds: Vec<sys::CtrlCmd<'_>>) -> Result<Vec<Result<()>>> {
+        let mut reqs: Vec<_> = cmds.into_iter().map(CtrlRequest::new).collect();
+        let results = self.transport.execute(&mut reqs)?;
+
+        Ok(reqs
+            .iter()
+            .zip(results)
+            .map(|(req, res)| req.result(res))
+            .collect())
+    }
+
+    // `GetDevInfo2` was introduced along with the unprivileged devices
+    fn get_dev_info_op(&self) -> CtrlOp {
+        if self.features.contains(DeviceFlags::Unprivileged) {
+            CtrlOp::GetDevInfo2
+        } else {
+            CtrlOp::GetDevInfo
+        }
+    }
+
+    // The kernel driver checks the permissions on unprivileged devices against
+    // their char device path, which must prefix the command buffer. `GetDevInfo2`
+    // always carries it, since the caller may not know the device's kind yet.
+    fn needs_dev_path(&mut self, op: CtrlOp, dev_id: u32) -> Result<bool> {
+        if op == CtrlOp::GetDevInfo2 {
+            return Ok(true);
+        }
+
+        if !self.features.contains(DeviceFlags::Unprivileged) {
+            return Ok(false);
+        }
+
+        if let Some(&unprivileged) = self.unprivileged.get(&dev_id) {
+            return Ok(unprivileged);
+        }
 
-        Ok(info.into())
+        let info = self.get_device_info(dev_id)?;
+        Ok(info.flags.contains(DeviceFlags::Unprivileged))
+    }
+
+    // Sends a command to the device `dev_id`, prefixing its buffer (if any)
+    // with the device's char device path when required
+    fn device_cmd<T: Copy>(
+        &mut self,
+        op: CtrlOp,
+        dev_id: u32,
+        data: u64,
+        buf: Option<&mut T>,
+    ) -> Result<()> {
+        let dev_path = self.needs_dev_path(op, dev_id)?;
+
+        let cmd = sys::CtrlCmd::new(op, dev_id, self.encoding).data(data);
+
+        if dev_path {
+            let mut path_buf = sys::DevPathBuffer::new(dev_id, buf.as_deref());
+            self.execute(cmd.dev_path_buffer(&mut path_buf))?;
+
+            if let (Some(buf), Some(payload)) = (buf, path_buf.payload()) {
+                *buf = payload;
+            }
+        } else {
+            match buf {
+                Some(buf) => self.execute(cmd.buffer(buf))?,
+                None => self.execute(cmd)?,
+            }
+        }
+
+        Ok(())
     }
 }
 
+// The kernel driver waits forever if the timeout is zero
+fn quiesce_timeout_ms(timeout: Option<Duration>) -> u64 {
+    timeout.map_or(0, |timeout| {
+        u64::try_from(timeout.as_millis())
+            .unwrap_or(u64::MAX)
+            .max(1)
+    })
+}
+
 /// Device information
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceInfo {
     /// Device id
     pub dev_id: u32,
     /// User space server PID
     pub srv_pid: i32,
-    /// Device state
+    /// Device state, `true` if the device is live
     pub active: bool,
+    /// Device state
+    pub state: DeviceState,
     /// Number of hardware queues
     pub nr_hw_queues: u16,
     /// Queue depth
@@ -225,15 +620,18 @@ pub struct DeviceInfo {
     pub max_io_buf_bytes: u32,
     /// Device flags
     pub flags: DeviceFlags,
+    /// User ID of the device's owner, i.e., the user that added it
+    pub owner_uid: u32,
+    /// Group ID of the device's owner
+    pub owner_gid: u32,
 }
 
 bitflags! {
     /// 64bit flags that will be copied back to userspace as feature negotiation result
     #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct DeviceFlags: u64 {
-        /// zero copy requires 4k block size, and can remap ublk driver's io
-        /// request into ublksrv's vm space.
-        /// Kernel driver is not ready to support zero copy.
+        /// The request buffer can be registered as an io_uring fixed buffer,
+        /// the queue only supports the automatic registration, see `AutoBufReg`.
         const ZeroCopy = sys::DevInfo::SUPPORT_ZERO_COPY;
 
         /// Force to complete io cmd via io_uring_cmd_complete_in_task so that
@@ -243,21 +641,121 @@ bitflags! {
         /// User should issue io cmd again for write requests to set io buffer address
         /// and copy data from bio vectors to the userspace io buffer.
         /// In this mode, task_work is not used.
+        /// The queue does it, the I/O buffers are allocated when a request needs
+        /// one, see [`BufferPool`](crate::queue::BufferPool).
         const NeedGetData = sys::DevInfo::NEED_GET_DATA;
+
+        /// The device survives the server's death, it is quiesced until a new
+        /// server recovers it. The in-flight I/O is failed.
+        const UserRecovery = sys::DevInfo::USER_RECOVERY;
+
+        /// Like `UserRecovery`, but the in-flight I/O is re-issued to the new server.
+        /// It requires `UserRecovery`.
+        const UserRecoveryReissue = sys::DevInfo::USER_RECOVERY_REISSUE;
+
+        /// The commands opcodes are encoded as ioctl(2) requests,
+        /// it's always set by the kernel drivers that support it.
+        const CmdIoctlEncode = sys::DevInfo::CMD_IOCTL_ENCODE;
+
+        /// The device can be added and managed by unprivileged users, the kernel
+        /// driver checks their permissions on the device's char device.
+        const Unprivileged = sys::DevInfo::UNPRIVILEGED_DEV;
+
+        /// The request data is copied through the device's char device, by the queue
+        /// or directly by the target, instead of by the kernel driver into the queue's
+        /// I/O buffers, see [`UblkQueue::direct_copy()`](crate::queue::UblkQueue::direct_copy).
+        const UserCopy = sys::DevInfo::USER_COPY;
+
+        /// Zoned block device, it requires `UserCopy` and the zoned parameters,
+        /// see [`DeviceParamZoned`].
+        const Zoned = sys::DevInfo::ZONED;
+
+        /// The device can be resized while it's live,
+        /// see [`UblkCtrl::update_size()`].
+        const UpdateSize = sys::DevInfo::UPDATE_SIZE;
+
+        /// The request buffer is registered as a fixed buffer of the queue's
+        /// io_uring, and unregistered when the request is committed.
+        /// The target does I/O on it without copying the request data,
+        /// see [`FixedBuffer`](crate::queue::FixedBuffer).
+        const AutoBufReg = sys::DevInfo::AUTO_BUF_REG;
+
+        /// The device can be quiesced while it's live, see [`UblkCtrl::quiesce_device()`].
+        /// It requires `UserRecovery`.
+        const Quiesce = sys::DevInfo::QUIESCE;
     }
 }
 
+/// Control command sent to the kernel driver
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CtrlOp {
+    /// Get the CPU affinity of a queue
+    GetQueueAffinity = 1,
+    /// Get the device information
+    GetDevInfo = 2,
+    /// Add a device
+    AddDev = 4,
+    /// Delete a device
+    DelDev = 5,
+    /// Start a device
+    StartDev = 6,
+    /// Stop a device
+    StopDev = 7,
+    /// Set the device parameters
+    SetParams = 8,
+    /// Get the device parameters
+    GetParams = 9,
+    /// Start the user recovery of a device
+    StartUserRecovery = 0x10,
+    /// End the user recovery of a device
+    EndUserRecovery = 0x11,
+    /// Get the device information, with the char device path for permission checks
+    GetDevInfo2 = 0x12,
+    /// Get the features supported by the kernel driver, only ioctl-encoded
+    GetFeatures = 0x13,
+    /// Change the size of a live device, only ioctl-encoded
+    UpdateSize = 0x15,
+    /// Quiesce a live device, only ioctl-encoded
+    QuiesceDev = 0x16,
+}
+
+/// Encoding of the commands opcodes sent to the kernel driver
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CmdEncoding {
+    /// Raw command numbers, newer kernels may not support them
+    Legacy,
+    /// Opcodes encoded as ioctl(2) requests, e.g., `_IOWR('u', nr, size)`
+    Ioctl,
+}
+
+/// Device state
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DeviceState {
+    /// The device is not started, or it was stopped
+    Dead,
+    /// The device is started and serving I/O
+    Live,
+    /// The device's server died, the device is waiting to be recovered
+    Quiesced,
+    /// State not known by this library
+    Unknown(u16),
+}
+
 /// Options and flags which can be used to configure how a ublk device is created.
 ///
 /// This builder exposes the ability to configure how a device is created and
 /// the feature negotiation.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceOptions {
     dev_id: u32,
     nr_hw_queues: u16,
     queue_depth: u16,
     max_io_buf_bytes: u32,
     flags: DeviceFlags,
+    negotiate_features: bool,
 }
 
 impl DeviceOptions {
@@ -284,6 +782,7 @@ impl DeviceOptions {
             queue_depth: Self::DEFAULT_QUEUE_DEPTH,
             max_io_buf_bytes: Self::DEFAULT_BUF_SIZE,
             flags: DeviceFlags::empty(),
+            negotiate_features: false,
         }
     }
 
@@ -333,6 +832,14 @@ impl DeviceOptions {
         self.flags = flags;
         self
     }
+
+    /// Enables the feature negotiation, the requested [`DeviceFlags`] are
+    /// intersected with the ones supported by the kernel driver
+    #[must_use]
+    pub const fn negotiate_features(mut self, negotiate: bool) -> Self {
+        self.negotiate_features = negotiate;
+        self
+    }
 }
 
 impl Default for DeviceOptions {
@@ -342,45 +849,354 @@ impl Default for DeviceOptions {
 }
 
 /// Device parameters
+///
+/// They can be built from byte sizes with [`DeviceParams::builder()`],
+/// which checks them before they are sent to the kernel driver.
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceParams {
     /// Device attributes
     pub attrs: DeviceAttr,
-    /// TODO
+    /// Logical block size, as a power of 2 exponent
     pub logical_bs_shift: u8,
-    /// TODO
+    /// Physical block size, as a power of 2 exponent
     pub physical_bs_shift: u8,
-    /// TODO
+    /// Optimal I/O size, as a power of 2 exponent
     pub io_opt_shift: u8,
-    /// TODO
+    /// Minimum I/O size, as a power of 2 exponent
     pub io_min_shift: u8,
-    /// TODO
+    /// Maximum request size in sectors
     pub max_sectors: u32,
-    /// TODO
+    /// Requests don't cross boundaries of this number of sectors, zero means none.
+    /// It's the zone size of zoned devices.
     pub chunk_sectors: u32,
-    /// TODO
+    /// Device size in sectors
     pub dev_sectors: u64,
-    /// TODO
+    /// The request segments, but the first and the last ones, are aligned
+    /// to this mask plus one, zero means no constraint
     pub virt_boundary_mask: u64,
     /// Device optional discard parameters
     pub discard: Option<DeviceParamDiscard>,
+    /// Device numbers of the device's nodes, only returned by
+    /// [`UblkCtrl::get_device_parameters()`], it's ignored when setting the parameters
+    pub devt: Option<DeviceParamDevt>,
+    /// Zoned device parameters, mandatory for [`DeviceFlags::Zoned`] devices.
+    /// The zone size is set by `chunk_sectors`.
+    pub zoned: Option<DeviceParamZoned>,
+    /// Device optional DMA alignment mask of the I/O buffers, i.e., the alignment
+    /// minus one. The alignment must be a power of 2 smaller than the page size.
+    pub dma_alignment: Option<u32>,
+    /// Device optional segment limits
+    pub segment: Option<DeviceParamSegment>,
+}
+
+impl DeviceParams {
+    /// Returns a builder of the parameters of the device described by `info`
+    #[must_use]
+    pub const fn builder(info: &DeviceInfo) -> DeviceParamsBuilder {
+        DeviceParamsBuilder::new(info)
+    }
+}
+
+/// Builder of checked [`DeviceParams`], the sizes are in bytes
+///
+/// The block sizes default to 512 bytes, and the maximum request size to the
+/// device's `max_io_buf_bytes`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DeviceParamsBuilder {
+    max_io_buf_bytes: u32,
+    zoned_dev: bool,
+    user_copy: bool,
+    size: u64,
+    logical_block_size: u32,
+    physical_block_size: u32,
+    io_min_size: u32,
+    io_opt_size: u32,
+    max_io_size: Option<u32>,
+    chunk_size: u32,
+    attrs: DeviceAttr,
+    discard: Option<DeviceParamDiscard>,
+    zoned: Option<DeviceParamZoned>,
+}
+
+impl DeviceParamsBuilder {
+    const SECTOR_SIZE: u32 = 1 << 9;
+
+    /// Builder of the parameters of the device described by `info`
+    #[must_use]
+    pub const fn new(info: &DeviceInfo) -> Self {
+        Self {
+            max_io_buf_bytes: info.max_io_buf_bytes,
+            zoned_dev: info.flags.contains(DeviceFlags::Zoned),
+            user_copy: info.flags.contains(DeviceFlags::UserCopy),
+            size: 0,
+            logical_block_size: Self::SECTOR_SIZE,
+            physical_block_size: Self::SECTOR_SIZE,
+            io_min_size: Self::SECTOR_SIZE,
+            io_opt_size: Self::SECTOR_SIZE,
+            max_io_size: None,
+            chunk_size: 0,
+            attrs: DeviceAttr::empty(),
+            discard: None,
+            zoned: None,
+        }
+    }
+
+    /// Sets the device size, a multiple of the logical block size
+    #[must_use]
+    pub const fn size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Sets the logical block size, a power of 2 from 512 to the page size
+    #[must_use]
+    pub const fn logical_block_size(mut self, size: u32) -> Self {
+        self.logical_block_size = size;
+        self
+    }
+
+    /// Sets the physical block size, a power of 2 not smaller than the logical block size
+    #[must_use]
+    pub const fn physical_block_size(mut self, size: u32) -> Self {
+        self.physical_block_size = size;
+        self
+    }
+
+    /// Sets the minimum I/O size, a power of 2 not smaller than the logical block size
+    #[must_use]
+    pub const fn io_min_size(mut self, size: u32) -> Self {
+        self.io_min_size = size;
+        self
+    }
+
+    /// Sets the optimal I/O size, a power of 2 not smaller than the logical block size
+    #[must_use]
+    pub const fn io_opt_size(mut self, size: u32) -> Self {
+        self.io_opt_size = size;
+        self
+    }
+
+    /// Sets the maximum request size, a multiple of the logical block size
+    /// not larger than the device's `max_io_buf_bytes`
+    #[must_use]
+    pub const fn max_io_size(mut self, size: u32) -> Self {
+        self.max_io_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the chunks that requests don't cross, a power of 2
+    /// multiple of the logical block size. It's the zone size of zoned devices.
+    #[must_use]
+    pub const fn chunk_size(mut self, size: u32) -> Self {
+        self.chunk_size = size;
+        self
+    }
+
+    /// Sets the device attributes
+    #[must_use]
+    pub const fn attrs(mut self, attrs: DeviceAttr) -> Self {
+        self.attrs = attrs;
+        self
+    }
+
+    /// Enables discard, the granularity and the alignment are in bytes,
+    /// the maximum discard and write zeroes sizes in sectors
+    #[must_use]
+    pub const fn discard(mut self, discard: DeviceParamDiscard) -> Self {
+        self.discard = Some(discard);
+        self
+    }
+
+    /// Sets the zoned parameters, mandatory for [`DeviceFlags::Zoned`] devices
+    #[must_use]
+    pub const fn zoned(mut self, zoned: DeviceParamZoned) -> Self {
+        self.zoned = Some(zoned);
+        self
+    }
+
+    /// Checks the parameters and builds them
+    /// # Errors
+    ///
+    pub fn build(&self) -> Result<DeviceParams> {
+        let invalid = |msg: String| Err(Error::InvalidParams(msg));
+
+        let lbs = self.logical_block_size;
+        let page_size = crate::queue::page_size();
+        if !lbs.is_power_of_two() || lbs < Self::SECTOR_SIZE || lbs as usize > page_size {
+            return invalid(format!(
+                "logical block size {lbs} is not a power of 2 from 512 to {page_size}"
+            ));
+        }
+
+        for (name, size) in [
+            ("physical block size", self.physical_block_size),
+            ("minimum I/O size", self.io_min_size),
+            ("optimal I/O size", self.io_opt_size),
+        ] {
+            if !size.is_power_of_two() || size < lbs {
+                return invalid(format!(
+                    "{name} {size} is not a power of 2 not smaller than the logical block size {lbs}"
+                ));
+            }
+        }
+
+        if self.size == 0 || !self.size.is_multiple_of(u64::from(lbs)) {
+            return invalid(format!(
+                "size {} is not a multiple of the logical block size {lbs}",
+                self.size
+            ));
+        }
+
+        let max_io_size = self.max_io_size.unwrap_or(self.max_io_buf_bytes);
+        if max_io_size < lbs
+            || !max_io_size.is_multiple_of(lbs)
+            || max_io_size > self.max_io_buf_bytes
+        {
+            return invalid(format!(
+                "maximum I/O size {max_io_size} is not a multiple of the logical block size {lbs} \
+                 up to the device's max_io_buf_bytes {}",
+                self.max_io_buf_bytes
+            ));
+        }
+
+        if self.chunk_size != 0 && (!self.chunk_size.is_power_of_two() || self.chunk_size < lbs) {
+            return invalid(format!(
+                "chunk size {} is not a power of 2 multiple of the logical block size {lbs}",
+                self.chunk_size
+            ));
+        }
+
+        if let Some(discard) = &self.discard {
+            let granularity = discard.discard_granularity;
+            if granularity == 0 || !granularity.is_multiple_of(lbs) {
+                return invalid(format!(
+                    "discard granularity {granularity} is not a multiple of the logical block size {lbs}"
+                ));
+            }
+            if !discard.discard_alignment.is_multiple_of(lbs)
+                || discard.discard_alignment >= granularity
+            {
+                return invalid(format!(
+                    "discard alignment {} is not a multiple of the logical block size {lbs} \
+                     smaller than the discard granularity {granularity}",
+                    discard.discard_alignment
+                ));
+            }
+            // the kernel driver only supports single segment discards
+            if discard.max_discard_sectors != 0 && discard.max_discard_segments != 1 {
+                return invalid(format!(
+                    "{} discard segments, only 1 is supported",
+                    discard.max_discard_segments
+                ));
+            }
+        }
+
+        if self.zoned_dev {
+            // zone append requests return the written sector with the data copy
+            if !self.user_copy {
+                return invalid("zoned device without user copy".to_string());
+            }
+            let Some(zoned) = &self.zoned else {
+                return invalid("zoned device without zoned parameters".to_string());
+            };
+            if self.chunk_size == 0 {
+                return invalid("zoned device without zone size (chunk size)".to_string());
+            }
+            if zoned.max_zone_append_sectors == 0 {
+                return invalid("zoned device without maximum zone append size".to_string());
+            }
+        } else if self.zoned.is_some() {
+            return invalid("zoned parameters for a non zoned device".to_string());
+        }
+
+        Ok(DeviceParams {
+            attrs: self.attrs,
+            logical_bs_shift: lbs.trailing_zeros() as u8,
+            physical_bs_shift: self.physical_block_size.trailing_zeros() as u8,
+            io_opt_shift: self.io_opt_size.trailing_zeros() as u8,
+            io_min_shift: self.io_min_size.trailing_zeros() as u8,
+            max_sectors: max_io_size / Self::SECTOR_SIZE,
+            chunk_sectors: self.chunk_size / Self::SECTOR_SIZE,
+            dev_sectors: self.size / u64::from(Self::SECTOR_SIZE),
+            discard: self.discard,
+            zoned: self.zoned,
+            ..DeviceParams::default()
+        })
+    }
 }
 
 /// Device optional discard parameters
 #[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DeviceParamDiscard {
-    /// TODO
+    /// Offset in bytes of the first discard block from the device start
     pub discard_alignment: u32,
-    /// TODO
+    /// Discard block size in bytes
     pub discard_granularity: u32,
-    /// TODO
+    /// Maximum discard request size in sectors
     pub max_discard_sectors: u32,
-    /// TODO
+    /// Maximum write zeroes request size in sectors
     pub max_write_zeroes_sectors: u32,
-    /// TODO
+    /// Maximum number of segments of a discard request, only 1 is supported
     pub max_discard_segments: u16,
 }
 
+/// Device numbers of the device's char (/dev/ublkcN) and block (/dev/ublkbN) nodes
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceParamDevt {
+    /// Char device major number
+    pub char_major: u32,
+    /// Char device minor number
+    pub char_minor: u32,
+    /// Block device major number, zero until the device is started
+    pub disk_major: u32,
+    /// Block device minor number, zero until the device is started
+    pub disk_minor: u32,
+}
+
+impl DeviceParamDevt {
+    /// Char device number
+    #[must_use]
+    pub fn char_devt(&self) -> libc::dev_t {
+        libc::makedev(self.char_major, self.char_minor)
+    }
+
+    /// Block device number, `None` until the device is started
+    #[must_use]
+    pub fn disk_devt(&self) -> Option<libc::dev_t> {
+        (self.disk_major != 0).then(|| libc::makedev(self.disk_major, self.disk_minor))
+    }
+}
+
+/// Device optional segment limits
+///
+/// Older kernel drivers silently ignore them, as with `dma_alignment`,
+/// [`UblkCtrl::get_device_parameters()`] only returns the ones in use.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceParamSegment {
+    /// Segment boundary mask, plus one it must be a power of 2 and at least 4096
+    pub seg_boundary_mask: u64,
+    /// Maximum segment size in bytes, at least 4096
+    pub max_segment_size: u32,
+    /// Maximum number of segments per request, it can't be zero
+    pub max_segments: u16,
+}
+
+/// Zoned device parameters
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceParamZoned {
+    /// Maximum number of open zones, zero means no limit
+    pub max_open_zones: u32,
+    /// Maximum number of active zones, zero means no limit
+    pub max_active_zones: u32,
+    /// Maximum size of a zone append request in sectors, it can't be zero
+    pub max_zone_append_sectors: u32,
+}
+
 bitflags! {
     /// Device Attributes flags
     #[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
@@ -395,3 +1211,224 @@ bitflags! {
         const Fua = sys::DevParamBasic::ATTR_FUA;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_IO_BUF_BYTES: u32 = 512 << 10;
+
+    fn builder(flags: DeviceFlags) -> DeviceParamsBuilder {
+        let info = DeviceInfo {
+            dev_id: 0,
+            srv_pid: -1,
+            active: false,
+            state: DeviceState::Dead,
+            nr_hw_queues: 1,
+            queue_depth: 64,
+            max_io_buf_bytes: MAX_IO_BUF_BYTES,
+            flags,
+            owner_uid: 0,
+            owner_gid: 0,
+        };
+        DeviceParams::builder(&info).size(1 << 30)
+    }
+
+    fn zoned_builder(flags: DeviceFlags) -> DeviceParamsBuilder {
+        builder(flags | DeviceFlags::Zoned)
+            .chunk_size(1 << 20)
+            .zoned(DeviceParamZoned {
+                max_zone_append_sectors: 256,
+                ..DeviceParamZoned::default()
+            })
+    }
+
+    fn discard(granularity: u32, alignment: u32) -> DeviceParamDiscard {
+        DeviceParamDiscard {
+            discard_alignment: alignment,
+            discard_granularity: granularity,
+            max_discard_sectors: 1024,
+            max_write_zeroes_sectors: 0,
+            max_discard_segments: 1,
+        }
+    }
+
+    fn assert_invalid(builder: DeviceParamsBuilder) {
+        let res = builder.build();
+        assert!(
+            matches!(res, Err(Error::InvalidParams(_))),
+            "unexpected {res:?}"
+        );
+    }
+
+    #[test]
+    fn defaults() {
+        let params = builder(DeviceFlags::empty()).build().unwrap();
+
+        assert_eq!(params.logical_bs_shift, 9);
+        assert_eq!(params.physical_bs_shift, 9);
+        assert_eq!(params.max_sectors, MAX_IO_BUF_BYTES >> 9);
+        assert_eq!(params.dev_sectors, (1 << 30) >> 9);
+        assert_eq!(params.discard, None);
+        assert_eq!(params.zoned, None);
+    }
+
+    #[test]
+    fn size() {
+        assert_invalid(builder(DeviceFlags::empty()).size(0));
+        assert_invalid(
+            builder(DeviceFlags::empty())
+                .logical_block_size(4096)
+                .size(4096 + 512),
+        );
+    }
+
+    #[test]
+    fn logical_block_size() {
+        let params = builder(DeviceFlags::empty())
+            .logical_block_size(4096)
+            .physical_block_size(4096)
+            .io_min_size(4096)
+            .io_opt_size(4096)
+            .build()
+            .unwrap();
+        assert_eq!(params.logical_bs_shift, 12);
+
+        for lbs in [0, 256, 1000, 3 << 9, crate::queue::page_size() as u32 * 2] {
+            assert_invalid(builder(DeviceFlags::empty()).logical_block_size(lbs));
+        }
+    }
+
+    #[test]
+    fn physical_block_size() {
+        let params = builder(DeviceFlags::empty())
+            .physical_block_size(4096)
+            .build()
+            .unwrap();
+        assert_eq!(params.physical_bs_shift, 12);
+
+        assert_invalid(builder(DeviceFlags::empty()).physical_block_size(3 << 9));
+        assert_invalid(
+            builder(DeviceFlags::empty())
+                .logical_block_size(4096)
+                .physical_block_size(512),
+        );
+    }
+
+    #[test]
+    fn io_sizes() {
+        assert_invalid(builder(DeviceFlags::empty()).io_min_size(256));
+        assert_invalid(builder(DeviceFlags::empty()).io_opt_size(1536));
+    }
+
+    #[test]
+    fn max_io_size() {
+        let params = builder(DeviceFlags::empty())
+            .max_io_size(64 << 10)
+            .build()
+            .unwrap();
+        assert_eq!(params.max_sectors, 128);
+
+        let params = builder(DeviceFlags::empty())
+            .max_io_size(MAX_IO_BUF_BYTES)
+            .build()
+            .unwrap();
+        assert_eq!(params.max_sectors, MAX_IO_BUF_BYTES >> 9);
+
+        assert_invalid(builder(DeviceFlags::empty()).max_io_size(0));
+        assert_invalid(builder(DeviceFlags::empty()).max_io_size(1000));
+        assert_invalid(builder(DeviceFlags::empty()).max_io_size(MAX_IO_BUF_BYTES + 512));
+        assert_invalid(
+            builder(DeviceFlags::empty())
+                .logical_block_size(4096)
+                .physical_block_size(4096)
+                .io_min_size(4096)
+                .io_opt_size(4096)
+                .max_io_size(2048),
+        );
+    }
+
+    #[test]
+    fn chunk_size() {
+        let params = builder(DeviceFlags::empty())
+            .chunk_size(1 << 20)
+            .build()
+            .unwrap();
+        assert_eq!(params.chunk_sectors, 2048);
+
+        assert_invalid(builder(DeviceFlags::empty()).chunk_size(3 << 20));
+        assert_invalid(builder(DeviceFlags::empty()).chunk_size(256));
+    }
+
+    #[test]
+    fn discard_granularity() {
+        let params = builder(DeviceFlags::empty())
+            .discard(discard(4096, 0))
+            .build()
+            .unwrap();
+        assert_eq!(params.discard, Some(discard(4096, 0)));
+
+        assert_invalid(builder(DeviceFlags::empty()).discard(discard(0, 0)));
+        assert_invalid(builder(DeviceFlags::empty()).discard(discard(1000, 0)));
+        assert_invalid(
+            builder(DeviceFlags::empty())
+                .logical_block_size(4096)
+                .physical_block_size(4096)
+                .io_min_size(4096)
+                .io_opt_size(4096)
+                .discard(discard(512, 0)),
+        );
+    }
+
+    #[test]
+    fn discard_alignment() {
+        let params = builder(DeviceFlags::empty())
+            .discard(discard(4096, 1024))
+            .build()
+            .unwrap();
+        assert_eq!(params.discard, Some(discard(4096, 1024)));
+
+        assert_invalid(builder(DeviceFlags::empty()).discard(discard(4096, 100)));
+        assert_invalid(builder(DeviceFlags::empty()).discard(discard(4096, 4096)));
+    }
+
+    #[test]
+    fn discard_segments() {
+        let mut multi_segment = discard(4096, 0);
+        multi_segment.max_discard_segments = 2;
+        assert_invalid(builder(DeviceFlags::empty()).discard(multi_segment));
+
+        // the segments don't matter if discard is disabled
+        multi_segment.max_discard_sectors = 0;
+        builder(DeviceFlags::empty())
+            .discard(multi_segment)
+            .build()
+            .unwrap();
+    }
+
+    #[test]
+    fn zoned() {
+        let params = zoned_builder(DeviceFlags::UserCopy).build().unwrap();
+        assert_eq!(params.chunk_sectors, 2048);
+        assert_eq!(
+            params.zoned.map(|zoned| zoned.max_zone_append_sectors),
+            Some(256)
+        );
+    }
+
+    #[test]
+    fn zoned_without_user_copy() {
+        assert_invalid(zoned_builder(DeviceFlags::empty()));
+    }
+
+    #[test]
+    fn zoned_without_parameters() {
+        assert_invalid(builder(DeviceFlags::Zoned | DeviceFlags::UserCopy).chunk_size(1 << 20));
+        assert_invalid(zoned_builder(DeviceFlags::UserCopy).chunk_size(0));
+        assert_invalid(zoned_builder(DeviceFlags::UserCopy).zoned(DeviceParamZoned::default()));
+        assert_invalid(builder(DeviceFlags::UserCopy).zoned(DeviceParamZoned {
+            max_zone_append_sectors: 256,
+            ..DeviceParamZoned::default()
+        }));
+    }
+}
@@ -1,24 +1,73 @@
 // SPDX-License-Identifier: MIT
 
-use crate::control::{DeviceAttr, DeviceFlags, DeviceInfo, DeviceParamDiscard, DeviceParams};
+use crate::control::{
+    CmdEncoding, CtrlOp, DeviceAttr, DeviceFlags, DeviceInfo, DeviceOptions, DeviceParamDevt,
+    DeviceParamDiscard, DeviceParamSegment, DeviceParamZoned, DeviceParams, DeviceState,
+};
+use crate::queue::UblkQueue;
 use io_uring::opcode::UringCmd80;
+use io_uring::squeue;
 use io_uring::types::Fixed;
-use io_uring::{cqueue, squeue, IoUring};
 use std::marker::PhantomData;
-use std::{io, mem};
+use std::{mem, ptr, slice};
+
+impl CtrlOp {
+    // Returns the opcode to be sent to the kernel driver, any new command
+    // (not available as legacy opcode) is always ioctl-encoded.
+    pub(crate) const fn opcode(self, encoding: CmdEncoding) -> u32 {
+        const SIZE: usize = mem::size_of::<CmdData>();
+
+        match self {
+            Self::GetFeatures => CmdEncoding::Ioctl.ior(self as u32, SIZE),
+            Self::UpdateSize | Self::QuiesceDev => CmdEncoding::Ioctl.iowr(self as u32, SIZE),
+            Self::GetQueueAffinity | Self::GetDevInfo | Self::GetParams | Self::GetDevInfo2 => {
+                encoding.ior(self as u32, SIZE)
+            }
+            Self::AddDev
+            | Self::DelDev
+            | Self::StartDev
+            | Self::StopDev
+            | Self::SetParams
+            | Self::StartUserRecovery
+            | Self::EndUserRecovery => encoding.iowr(self as u32, SIZE),
+        }
+    }
+}
 
-// Control command opcodes handled by ublk kernel driver.
-#[repr(u32)]
-#[derive(Debug, Copy, Clone)]
-pub enum CtrlOp {
-    GetQueueAffinity = 1,
-    GetDevInfo = 2,
-    AddDev = 4,
-    DelDev = 5,
-    StartDev = 6,
-    StopDev = 7,
-    SetParams = 8,
-    GetParams = 9,
+// ioctl(2) request encoding, see include/uapi/asm-generic/ioctl.h
+const IOC_NRSHIFT: u32 = 0;
+const IOC_TYPESHIFT: u32 = 8;
+const IOC_SIZESHIFT: u32 = 16;
+const IOC_DIRSHIFT: u32 = 30;
+
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+// ioctl type of all the ublk commands
+const UBLK_IOC_TYPE: u32 = b'u' as u32;
+
+impl CmdEncoding {
+    const fn ioc(self, dir: u32, nr: u32, size: usize) -> u32 {
+        match self {
+            Self::Legacy => nr,
+            Self::Ioctl => {
+                (dir << IOC_DIRSHIFT)
+                    | ((size as u32) << IOC_SIZESHIFT)
+                    | (UBLK_IOC_TYPE << IOC_TYPESHIFT)
+                    | (nr << IOC_NRSHIFT)
+            }
+        }
+    }
+
+    // _IOR('u', nr, size)
+    pub(crate) const fn ior(self, nr: u32, size: usize) -> u32 {
+        self.ioc(IOC_READ, nr, size)
+    }
+
+    // _IOWR('u', nr, size)
+    pub(crate) const fn iowr(self, nr: u32, size: usize) -> u32 {
+        self.ioc(IOC_READ | IOC_WRITE, nr, size)
+    }
 }
 
 // Since we initialize the ring with IORING_SETUP_SQE128,
@@ -38,7 +87,12 @@ struct CmdData {
     len: u16,
     addr: u64,
     // cmd op inline data
-    data: [u64; 2],
+    data: u64,
+    // length of the char device path prepended to the IN/OUT buffer,
+    // required by the unprivileged devices
+    dev_path_len: u16,
+    _pad: u16,
+    _reserved: u32,
 }
 
 const _: () = assert!(
@@ -66,14 +120,15 @@ impl From<CmdData> for IoUringCmdData {
 //
 // ```
 //  let mut info = DevInfo::new();
-//  let cmd = CtrlCmd::new(CtrlOp::GetDevInfo, 0).buffer(&mut info);
+//  let cmd = CtrlCmd::new(CtrlOp::GetDevInfo, 0, encoding).buffer(&mut info);
 //
 //  drop(info);
-//  cmd.submit_and_wait(uniq, &mut ring);
+//  let sqe = cmd.prepare(uniq);
 // ```
 #[derive(Debug, Copy, Clone)]
 pub struct CtrlCmd<'a> {
     op: CtrlOp,
+    encoding: CmdEncoding,
     lifetime: PhantomData<&'a mut ()>,
     cmd_data: CmdData,
 }
@@ -84,16 +139,20 @@ impl<'a> CtrlCmd<'a> {
     const QUEUE_IGNORE_ID: u16 = u16::MAX;
 
     #[inline]
-    pub fn new(op: CtrlOp, dev_id: u32) -> Self {
+    pub fn new(op: CtrlOp, dev_id: u32, encoding: CmdEncoding) -> Self {
         Self {
             op,
+            encoding,
             lifetime: PhantomData,
             cmd_data: CmdData {
                 dev_id,
                 _queue_id: Self::QUEUE_IGNORE_ID, // unused, only checked in the AddDev command
                 len: 0,
                 addr: 0,
-                data: [0, 0], // data[1] is unused
+                data: 0,
+                dev_path_len: 0,
+                _pad: 0,
+                _reserved: 0,
             },
         }
     }
@@ -105,43 +164,132 @@ impl<'a> CtrlCmd<'a> {
         self
     }
 
+    #[inline]
+    pub fn dev_path_buffer<T>(mut self, buf: &'a mut DevPathBuffer<T>) -> Self {
+        self.cmd_data.addr = buf.bytes.as_mut_ptr() as u64;
+        self.cmd_data.len = buf.bytes.len() as u16;
+        self.cmd_data.dev_path_len = buf.path_len as u16;
+        self
+    }
+
     #[inline]
     pub fn data(mut self, data: u64) -> Self {
-        // data[1] is unused, should we rename this method
-        // to data0() and add data1()?
-        self.cmd_data.data = [data, 0];
+        self.cmd_data.data = data;
         self
     }
 
-    pub fn submit_and_wait(
-        &self,
-        uniq: u64,
-        ring: &mut IoUring<squeue::Entry128, cqueue::Entry32>,
-    ) -> crate::Result<()> {
-        let cmd = UringCmd80::new(Fixed(0), self.op as u32)
+    #[inline]
+    pub const fn op(&self) -> CtrlOp {
+        self.op
+    }
+
+    #[inline]
+    pub const fn opcode(&self) -> u32 {
+        self.op.opcode(self.encoding)
+    }
+
+    #[inline]
+    pub const fn dev_id(&self) -> u32 {
+        self.cmd_data.dev_id
+    }
+
+    #[inline]
+    pub const fn queue_id(&self) -> u16 {
+        self.cmd_data._queue_id
+    }
+
+    #[inline]
+    pub const fn arg(&self) -> u64 {
+        self.cmd_data.data
+    }
+
+    #[inline]
+    pub const fn dev_path_len(&self) -> u16 {
+        self.cmd_data.dev_path_len
+    }
+
+    // Address and length of the backing buffer, valid for the lifetime 'a
+    #[inline]
+    pub const fn raw_buffer(&self) -> (*mut u8, usize) {
+        (self.cmd_data.addr as *mut u8, self.cmd_data.len as usize)
+    }
+
+    // Builds the submission entry, the caller must guarantee that the backing
+    // buffer (if any) remains valid until the command completes.
+    #[inline]
+    pub fn prepare(&self, uniq: u64) -> squeue::Entry128 {
+        UringCmd80::new(Fixed(0), self.op.opcode(self.encoding))
             .cmd(self.cmd_data.into())
             .build()
-            .user_data(uniq);
-
-        // SAFETY: Since we block on the submission the command buffer will be valid
-        // until the submission completes.
-        unsafe { ring.submission().push(&cmd) }?;
-        let res = ring.submit_and_wait(1)?;
-        assert_eq!(res, 1);
+            .user_data(uniq)
+    }
 
-        let mut cq = ring.completion();
-        let cqe = cq.next().expect("completed ctrl command");
-        assert_eq!(uniq, cqe.user_data());
+    // Unbinds the command from its buffer's lifetime, the caller must
+    // guarantee that the buffer (if any) outlives the returned command.
+    #[cfg(any(test, feature = "mock"))]
+    #[inline]
+    pub unsafe fn detach(self) -> CtrlCmd<'static> {
+        CtrlCmd {
+            op: self.op,
+            encoding: self.encoding,
+            lifetime: PhantomData,
+            cmd_data: self.cmd_data,
+        }
+    }
 
-        let res = cqe.result();
-        if res == 0 {
+    // Converts the command's completion result
+    #[inline]
+    pub fn result(&self, res: i32) -> crate::Result<()> {
+        if res >= 0 {
             Ok(())
         } else {
-            Err(io::Error::from_raw_os_error(-res).into())
+            Err(crate::Error::ctrl(self.op, self.cmd_data.dev_id, -res))
         }
     }
 }
 
+// Command IN/OUT buffer prefixed with the device's char device path, the
+// kernel driver uses it to check the permissions on unprivileged devices.
+#[derive(Debug)]
+pub struct DevPathBuffer<T> {
+    bytes: Vec<u8>,
+    path_len: usize,
+    payload: PhantomData<T>,
+}
+
+impl<T: Copy> DevPathBuffer<T> {
+    pub fn new(dev_id: u32, payload: Option<&T>) -> Self {
+        let mut bytes = format!("{}{}", UblkQueue::CDEV_PATH_PREFIX, dev_id).into_bytes();
+        let path_len = bytes.len();
+
+        if let Some(payload) = payload {
+            // SAFETY: `payload` is valid for reads of `size_of::<T>()` bytes.
+            let payload = unsafe {
+                slice::from_raw_parts((payload as *const T).cast::<u8>(), mem::size_of::<T>())
+            };
+            bytes.extend_from_slice(payload);
+        }
+
+        Self {
+            bytes,
+            path_len,
+            payload: PhantomData,
+        }
+    }
+
+    // Returns the (possibly updated by the kernel driver) payload, if any
+    pub fn payload(&self) -> Option<T> {
+        let payload = &self.bytes[self.path_len..];
+        if payload.is_empty() {
+            return None;
+        }
+        assert_eq!(payload.len(), mem::size_of::<T>());
+
+        // SAFETY: `payload` holds exactly one `T`, copied in `new()`.
+        Some(unsafe { ptr::read_unaligned(payload.as_ptr().cast::<T>()) })
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct DevInfo {
@@ -153,8 +301,12 @@ pub struct DevInfo {
     dev_id: u32,
     ublksrv_pid: i32,
     _pad1: u32,
-    flags: u64,        // feature flags
-    _unused: [u64; 4], // For libublksrv internal use, invisible to ublk driver
+    flags: u64,          // feature flags
+    _ublksrv_flags: u64, // For libublksrv internal use, invisible to ublk driver
+    owner_uid: u32,      // stored by the kernel driver
+    owner_gid: u32,      // stored by the kernel driver
+    _reserved1: u64,
+    _reserved2: u64,
 }
 
 impl DevInfo {
@@ -162,14 +314,13 @@ impl DevInfo {
     pub const NEW_DEV_ID: u32 = u32::MAX; // interpreted as '-1' by the kernel driver
 
     // Device state
-    #[allow(unused)]
     const STATE_DEV_DEAD: u16 = 0;
     const STATE_DEV_LIVE: u16 = 1;
+    const STATE_DEV_QUIESCED: u16 = 2;
 
     // Available feature flags
-    // zero copy requires 4k block size, and can remap ublk driver's io
-    // request into ublksrv's vm space.
-    // Kernel driver is not ready to support zero copy.
+    // The request buffer can be registered as an io_uring fixed buffer, so the
+    // server can do I/O on it without copying the request data.
     pub const SUPPORT_ZERO_COPY: u64 = 1 << 0;
 
     // Force to complete io cmd via io_uring_cmd_complete_in_task so that
@@ -181,6 +332,38 @@ impl DevInfo {
     // In this mode, task_work is not used.
     pub const NEED_GET_DATA: u64 = 1 << 2;
 
+    // The device is not deleted when the server dies, instead it is quiesced
+    // waiting for a new server to recover it, in-flight I/O is failed.
+    pub const USER_RECOVERY: u64 = 1 << 3;
+
+    // Like USER_RECOVERY, but in-flight I/O is re-issued to the new server.
+    pub const USER_RECOVERY_REISSUE: u64 = 1 << 4;
+
+    // The device can be created and managed by unprivileged users, it is owned
+    // by the user that added it.
+    pub const UNPRIVILEGED_DEV: u64 = 1 << 5;
+
+    // The commands opcodes are encoded as ioctl(2) requests.
+    pub const CMD_IOCTL_ENCODE: u64 = 1 << 6;
+
+    // The server copies the request data with pread(2)/pwrite(2) on the
+    // char device, instead of the kernel driver copying it into the io buffer.
+    pub const USER_COPY: u64 = 1 << 7;
+
+    // Zoned block device, it requires USER_COPY.
+    pub const ZONED: u64 = 1 << 8;
+
+    // The device size can be changed while it's live, with UpdateSize.
+    pub const UPDATE_SIZE: u64 = 1 << 10;
+
+    // The request buffer is registered automatically in the fixed buffers table
+    // of the ring issuing the fetch/commit command, at the index set in
+    // sqe->addr, and unregistered when the request is committed.
+    pub const AUTO_BUF_REG: u64 = 1 << 11;
+
+    // The device can be quiesced with QuiesceDev, it requires USER_RECOVERY.
+    pub const QUIESCE: u64 = 1 << 12;
+
     pub const MAX_BUF_SIZE: u32 = 1024 << 10;
     pub const MAX_NR_HW_QUEUES: u16 = 32;
     pub const MAX_QUEUE_DEPTH: u16 = 1024;
@@ -220,16 +403,61 @@ impl DevInfo {
     }
 }
 
+// Feature flags returned by the GetFeatures command (8 bytes long)
+pub type Features = u64;
+
+impl From<&DeviceOptions> for DevInfo {
+    fn from(options: &DeviceOptions) -> Self {
+        // if after cast `dev_id` < 0, it means requesting a new id
+        Self::new()
+            .dev_id(options.dev_id)
+            .max_io_buf_bytes(options.max_io_buf_bytes)
+            .nr_hw_queues(options.nr_hw_queues)
+            .queue_depth(options.queue_depth)
+            .flags(options.flags.bits())
+    }
+}
+
+impl From<&DeviceInfo> for DevInfo {
+    fn from(info: &DeviceInfo) -> Self {
+        Self {
+            nr_hw_queues: info.nr_hw_queues,
+            queue_depth: info.queue_depth,
+            state: match info.state {
+                DeviceState::Dead => Self::STATE_DEV_DEAD,
+                DeviceState::Live => Self::STATE_DEV_LIVE,
+                DeviceState::Quiesced => Self::STATE_DEV_QUIESCED,
+                DeviceState::Unknown(state) => state,
+            },
+            max_io_buf_bytes: info.max_io_buf_bytes,
+            dev_id: info.dev_id,
+            ublksrv_pid: info.srv_pid,
+            flags: info.flags.bits(),
+            owner_uid: info.owner_uid,
+            owner_gid: info.owner_gid,
+            ..Self::default()
+        }
+    }
+}
+
 impl From<DevInfo> for DeviceInfo {
     fn from(info: DevInfo) -> Self {
         Self {
             dev_id: info.dev_id,
             srv_pid: info.ublksrv_pid,
             active: info.state == DevInfo::STATE_DEV_LIVE,
+            state: match info.state {
+                DevInfo::STATE_DEV_DEAD => DeviceState::Dead,
+                DevInfo::STATE_DEV_LIVE => DeviceState::Live,
+                DevInfo::STATE_DEV_QUIESCED => DeviceState::Quiesced,
+                state => DeviceState::Unknown(state),
+            },
             nr_hw_queues: info.nr_hw_queues,
             queue_depth: info.queue_depth,
             max_io_buf_bytes: info.max_io_buf_bytes,
             flags: DeviceFlags::from_bits_truncate(info.flags),
+            owner_uid: info.owner_uid,
+            owner_gid: info.owner_gid,
         }
     }
 }
@@ -246,12 +474,20 @@ pub struct DevParams {
 
     basic: DevParamBasic,
     discard: DevParamDiscard,
+    devt: DevParamDevt,
+    zoned: DevParamZoned,
+    dma: DevParamDmaAlign,
+    segment: DevParamSegment,
 }
 
 impl DevParams {
     // Available DevParams::types flags
     const TYPE_BASIC: u32 = 1 << 0; // mandatory on SetParams
     const TYPE_DISCARD: u32 = 1 << 1; // optional
+    const TYPE_DEVT: u32 = 1 << 2; // read-only, only on GetParams
+    const TYPE_ZONED: u32 = 1 << 3; // mandatory for zoned devices
+    const TYPE_DMA_ALIGN: u32 = 1 << 4; // optional
+    const TYPE_SEGMENT: u32 = 1 << 5; // optional
 
     // Only used in GetParams
     pub fn empty() -> Self {
@@ -262,8 +498,56 @@ impl DevParams {
             types: 0,
             basic: DevParamBasic::default(),
             discard: DevParamDiscard::default(),
+            devt: DevParamDevt::default(),
+            zoned: DevParamZoned::default(),
+            dma: DevParamDmaAlign::default(),
+            segment: DevParamSegment::default(),
         }
     }
+
+    // Newer kernel drivers append new parameter types to the structure, older
+    // ones only know a prefix of it. Returns the length of the structure up to
+    // the last parameter type in `types`, i.e., the one of the first kernel
+    // driver version that supports all of them.
+    fn len_for(types: u32) -> u32 {
+        let end = if types & Self::TYPE_SEGMENT != 0 {
+            mem::offset_of!(Self, segment) + mem::size_of::<DevParamSegment>()
+        } else if types & Self::TYPE_DMA_ALIGN != 0 {
+            mem::offset_of!(Self, dma) + mem::size_of::<DevParamDmaAlign>()
+        } else if types & Self::TYPE_ZONED != 0 {
+            mem::offset_of!(Self, zoned) + mem::size_of::<DevParamZoned>()
+        } else if types & Self::TYPE_DEVT != 0 {
+            mem::offset_of!(Self, devt) + mem::size_of::<DevParamDevt>()
+        } else {
+            mem::offset_of!(Self, discard) + mem::size_of::<DevParamDiscard>()
+        };
+
+        end.next_multiple_of(mem::align_of::<Self>()) as u32
+    }
+
+    // The basic parameters are mandatory on SetParams
+    pub const fn has_basic(&self) -> bool {
+        (self.types & Self::TYPE_BASIC) != 0
+    }
+
+    // Sets the read-only device numbers, as the kernel driver does on GetParams
+    pub fn set_devt(&mut self, devt: &DeviceParamDevt) {
+        self.types |= Self::TYPE_DEVT;
+        self.len = self.len.max(Self::len_for(self.types));
+        self.devt = DevParamDevt {
+            char_major: devt.char_major,
+            char_minor: devt.char_minor,
+            disk_major: devt.disk_major,
+            disk_minor: devt.disk_minor,
+        };
+    }
+
+    // Returns `true` if the parameter type is set. On GetParams the kernel
+    // driver copies its whole structure (truncated to ours), and only sets the
+    // types it knows, so their data is always present regardless of `len`.
+    fn has(&self, ty: u32) -> bool {
+        (self.types & ty) != 0
+    }
 }
 
 #[repr(C)]
@@ -299,16 +583,81 @@ pub struct DevParamDiscard {
     _reserved0: u16,
 }
 
+// Read-only, filled by the kernel driver
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DevParamDevt {
+    char_major: u32,
+    char_minor: u32,
+    // only valid once the device is started
+    disk_major: u32,
+    disk_minor: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DevParamZoned {
+    max_open_zones: u32,
+    max_active_zones: u32,
+    max_zone_append_sectors: u32,
+    _reserved: [u8; 20],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DevParamDmaAlign {
+    alignment: u32, // alignment mask, i.e., alignment - 1
+    _pad: [u8; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct DevParamSegment {
+    // seg_boundary_mask + 1 needs to be power_of_2(), and the sum has
+    // to be >= 4096 (the kernel's minimum segment size)
+    seg_boundary_mask: u64,
+    // max_segment_size could be override by virt_boundary_mask, so be
+    // careful when setting both.
+    max_segment_size: u32,
+    max_segments: u16,
+    _pad: [u8; 2],
+}
+
 impl From<DevParams> for DeviceParams {
     fn from(p: DevParams) -> Self {
-        let discard = ((p.types & DevParams::TYPE_DISCARD) != 0).then_some(DeviceParamDiscard {
-            discard_alignment: p.discard.discard_alignment,
-            discard_granularity: p.discard.discard_granularity,
-            max_discard_sectors: p.discard.max_discard_sectors,
-            max_write_zeroes_sectors: p.discard.max_write_zeroes_sectors,
-            max_discard_segments: p.discard.max_discard_segments,
+        let discard = p
+            .has(DevParams::TYPE_DISCARD)
+            .then_some(DeviceParamDiscard {
+                discard_alignment: p.discard.discard_alignment,
+                discard_granularity: p.discard.discard_granularity,
+                max_discard_sectors: p.discard.max_discard_sectors,
+                max_write_zeroes_sectors: p.discard.max_write_zeroes_sectors,
+                max_discard_segments: p.discard.max_discard_segments,
+            });
+
+        let devt = p.has(DevParams::TYPE_DEVT).then_some(DeviceParamDevt {
+            char_major: p.devt.char_major,
+            char_minor: p.devt.char_minor,
+            disk_major: p.devt.disk_major,
+            disk_minor: p.devt.disk_minor,
+        });
+
+        let zoned = p.has(DevParams::TYPE_ZONED).then_some(DeviceParamZoned {
+            max_open_zones: p.zoned.max_open_zones,
+            max_active_zones: p.zoned.max_active_zones,
+            max_zone_append_sectors: p.zoned.max_zone_append_sectors,
         });
 
+        let dma_alignment = p.has(DevParams::TYPE_DMA_ALIGN).then_some(p.dma.alignment);
+
+        let segment = p
+            .has(DevParams::TYPE_SEGMENT)
+            .then_some(DeviceParamSegment {
+                seg_boundary_mask: p.segment.seg_boundary_mask,
+                max_segment_size: p.segment.max_segment_size,
+                max_segments: p.segment.max_segments,
+            });
+
         Self {
             attrs: DeviceAttr::from_bits_truncate(p.basic.attrs),
             logical_bs_shift: p.basic.logical_bs_shift,
@@ -320,6 +669,10 @@ impl From<DevParams> for DeviceParams {
             dev_sectors: p.basic.dev_sectors,
             virt_boundary_mask: p.basic.virt_boundary_mask,
             discard,
+            devt,
+            zoned,
+            dma_alignment,
+            segment,
         }
     }
 }
@@ -344,6 +697,23 @@ impl From<&DeviceParams> for DevParams {
             discard.into()
         });
 
+        p.zoned = d.zoned.map_or_else(DevParamZoned::default, |zoned| {
+            p.types |= Self::TYPE_ZONED;
+            zoned.into()
+        });
+
+        if let Some(alignment) = d.dma_alignment {
+            p.types |= Self::TYPE_DMA_ALIGN;
+            p.dma.alignment = alignment;
+        }
+
+        p.segment = d.segment.map_or_else(DevParamSegment::default, |segment| {
+            p.types |= Self::TYPE_SEGMENT;
+            segment.into()
+        });
+
+        p.len = Self::len_for(p.types);
+
         p
     }
 }
@@ -360,3 +730,25 @@ impl From<DeviceParamDiscard> for DevParamDiscard {
         }
     }
 }
+
+impl From<DeviceParamZoned> for DevParamZoned {
+    fn from(p: DeviceParamZoned) -> Self {
+        Self {
+            max_open_zones: p.max_open_zones,
+            max_active_zones: p.max_active_zones,
+            max_zone_append_sectors: p.max_zone_append_sectors,
+            _reserved: [0; 20],
+        }
+    }
+}
+
+impl From<DeviceParamSegment> for DevParamSegment {
+    fn from(p: DeviceParamSegment) -> Self {
+        Self {
+            seg_boundary_mask: p.seg_boundary_mask,
+            max_segment_size: p.max_segment_size,
+            max_segments: p.max_segments,
+            _pad: [0; 2],
+        }
+    }
+}
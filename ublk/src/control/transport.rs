@@ -0,0 +1,235 @@
+// SPDX-License-Identifier: MIT
+
+use crate::control::{sys, CtrlOp, UblkCtrl};
+use crate::error::Result;
+use io_uring::{cqueue, squeue, IoUring};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::slice;
+use std::sync::{Mutex, PoisonError};
+
+/// Transport of the control commands to the kernel driver
+///
+/// [`UblkCtrl`] builds the commands and interprets their results, the transport
+/// only delivers them. The default one is [`UringTransport`], with the `mock`
+/// feature `MockTransport` emulates the kernel driver in-process.
+///
+/// The commands are sent and their completions received independently, from
+/// any thread: several commands can be in flight, each one completing on its own.
+pub trait CtrlTransport: Send + Sync {
+    /// Sends the command without waiting for it to complete, its completion is
+    /// returned by [`reap()`](Self::reap) along with `user_data`
+    /// # Errors
+    ///
+    /// Fails if the command cannot be sent.
+    /// # Safety
+    ///
+    /// The command buffer must remain valid until the command's completion is reaped.
+    unsafe fn submit(&self, user_data: u64, req: &mut CtrlRequest<'_>) -> Result<()>;
+
+    /// Waits until at least one of the sent commands completes, returns the user
+    /// data and the result of each completed command: zero on success or a
+    /// negative errno
+    /// # Errors
+    ///
+    /// Fails only if the completions cannot be received.
+    fn reap(&self) -> Result<Vec<(u64, i32)>>;
+
+    /// Sends the commands and waits for all of them to complete, returns each
+    /// command's result in the same order
+    /// # Errors
+    ///
+    /// Fails only if the commands cannot be sent.
+    fn execute(&self, cmds: &mut [CtrlRequest<'_>]) -> Result<Vec<i32>> {
+        let mut results = vec![None; cmds.len()];
+
+        let mut submitted = Ok(());
+        let mut pending = 0;
+        for (idx, req) in cmds.iter_mut().enumerate() {
+            // SAFETY: the sent commands are all reaped before returning,
+            // their buffers are borrowed until then.
+            submitted = unsafe { self.submit(idx as u64, req) };
+            if submitted.is_err() {
+                break;
+            }
+            pending += 1;
+        }
+
+        while pending > 0 {
+            for (user_data, res) in self.reap()? {
+                let Some(result) = results.get_mut(user_data as usize) else {
+                    continue;
+                };
+                if result.replace(res).is_none() {
+                    pending -= 1;
+                }
+            }
+        }
+        submitted?;
+
+        Ok(results
+            .into_iter()
+            .map(|res| res.expect("completed ctrl command"))
+            .collect())
+    }
+}
+
+/// Control command, as sent to the kernel driver
+///
+/// The command buffer (if any) is borrowed from the caller, it holds the
+/// command's input and receives its output.
+#[derive(Debug)]
+pub struct CtrlRequest<'a> {
+    cmd: sys::CtrlCmd<'a>,
+}
+
+impl<'a> CtrlRequest<'a> {
+    pub(crate) const fn new(cmd: sys::CtrlCmd<'a>) -> Self {
+        Self { cmd }
+    }
+
+    // Unbinds the command from its buffer's lifetime, for transports completing
+    // it later on, see `CtrlTransport::submit()`
+    #[cfg(any(test, feature = "mock"))]
+    pub(crate) unsafe fn detach(&self) -> CtrlRequest<'static> {
+        CtrlRequest {
+            // SAFETY: the caller guarantees that the buffer outlives the command.
+            cmd: unsafe { self.cmd.detach() },
+        }
+    }
+
+    /// Command
+    #[must_use]
+    pub const fn op(&self) -> CtrlOp {
+        self.cmd.op()
+    }
+
+    /// Command opcode, encoded as expected by the kernel driver
+    #[must_use]
+    pub const fn opcode(&self) -> u32 {
+        self.cmd.opcode()
+    }
+
+    /// Target device id, `u32::MAX` to request a new id on [`CtrlOp::AddDev`]
+    #[must_use]
+    pub const fn dev_id(&self) -> u32 {
+        self.cmd.dev_id()
+    }
+
+    /// Target queue id, `u16::MAX` if the command is not for a queue
+    #[must_use]
+    pub const fn queue_id(&self) -> u16 {
+        self.cmd.queue_id()
+    }
+
+    /// Command argument, e.g., the server PID on [`CtrlOp::StartDev`]
+    #[must_use]
+    pub const fn data(&self) -> u64 {
+        self.cmd.arg()
+    }
+
+    /// Length of the char device path prefixing the buffer, zero if none
+    ///
+    /// The kernel driver checks the permissions on unprivileged devices
+    /// against it, the command's payload follows it.
+    #[must_use]
+    pub const fn dev_path_len(&self) -> u16 {
+        self.cmd.dev_path_len()
+    }
+
+    /// Command buffer, empty if the command has none
+    #[must_use]
+    pub fn buffer(&self) -> &[u8] {
+        let (addr, len) = self.cmd.raw_buffer();
+        if len == 0 {
+            return &[];
+        }
+        // SAFETY: the buffer is mutably borrowed by the command for 'a.
+        unsafe { slice::from_raw_parts(addr, len) }
+    }
+
+    /// Mutable command buffer, empty if the command has none
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        let (addr, len) = self.cmd.raw_buffer();
+        if len == 0 {
+            return &mut [];
+        }
+        // SAFETY: the buffer is mutably borrowed by the command for 'a.
+        unsafe { slice::from_raw_parts_mut(addr, len) }
+    }
+
+    // Converts the command's result
+    pub(crate) fn result(&self, res: i32) -> Result<()> {
+        self.cmd.result(res)
+    }
+}
+
+/// Control transport submitting the commands with `io_uring` to /dev/ublk-control
+pub struct UringTransport {
+    ring: IoUring<squeue::Entry128, cqueue::Entry32>,
+    // serialize the accesses to the submission and the completion queues
+    sq_lock: Mutex<()>,
+    cq_lock: Mutex<()>,
+    _ctrl_dev: File,
+}
+
+impl UringTransport {
+    /// Opens the control device
+    /// # Errors
+    ///
+    /// Returns [`Error::ModuleNotLoaded`](crate::Error::ModuleNotLoaded) if the
+    /// control device doesn't exist.
+    pub fn new() -> Result<Self> {
+        let ring = IoUring::generic_builder().build(32)?;
+
+        let ctrl_dev = UblkCtrl::open_ctrl_dev()?;
+
+        ring.submitter().register_files(&[ctrl_dev.as_raw_fd()])?;
+
+        Ok(Self {
+            ring,
+            sq_lock: Mutex::new(()),
+            cq_lock: Mutex::new(()),
+            _ctrl_dev: ctrl_dev,
+        })
+    }
+}
+
+impl CtrlTransport for UringTransport {
+    unsafe fn submit(&self, user_data: u64, req: &mut CtrlRequest<'_>) -> Result<()> {
+        let sqe = req.cmd.prepare(user_data);
+
+        let _sq = self.sq_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        // SAFETY: the submission queue is only accessed with `sq_lock` held,
+        // the caller guarantees that the buffer outlives the command.
+        unsafe { self.ring.submission_shared().push(&sqe) }
+            .map_err(|_| io::Error::from_raw_os_error(libc::EBUSY))?;
+
+        loop {
+            match self.ring.submit() {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                res => return res.map(|_| ()).map_err(Into::into),
+            }
+        }
+    }
+
+    fn reap(&self) -> Result<Vec<(u64, i32)>> {
+        let _cq = self.cq_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            // SAFETY: the completion queue is only accessed with `cq_lock` held.
+            let completions: Vec<_> = unsafe { self.ring.completion_shared() }
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            if !completions.is_empty() {
+                return Ok(completions);
+            }
+
+            // Nothing to submit, the submission queue is flushed on submit
+            match self.ring.submitter().submit_and_wait(1) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                res => res?,
+            };
+        }
+    }
+}
@@ -0,0 +1,476 @@
+// SPDX-License-Identifier: MIT
+
+#[cfg(any(test, feature = "mock"))]
+use crate::control::MockTransport;
+use crate::control::{DeviceInfo, DeviceOptions, DeviceParams, DeviceState, UblkCtrl};
+use crate::error::{Error, Result};
+use crate::queue::BufferPool;
+use crate::target::{BlockTarget, NewBufferPool, QueueThreads, TargetDriver};
+#[cfg(any(test, feature = "mock"))]
+use std::io;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// ublk device
+///
+/// It owns a started ublk device whose queues are served by a [`BlockTarget`],
+/// the device is stopped and deleted on drop.
+pub struct UblkDevice<T> {
+    ctrl: UblkCtrl,
+    info: DeviceInfo,
+    server: Server<T>,
+    queues: Option<QueueThreads>,
+}
+
+impl<T: BlockTarget + 'static> UblkDevice<T> {
+    /// Returns a [`DeviceBuilder`] to create a device served by `target`
+    #[must_use]
+    pub fn builder(target: T) -> DeviceBuilder<T> {
+        DeviceBuilder::new(target)
+    }
+
+    /// Reattach a new server to the device `dev_id`, whose previous server died
+    ///
+    /// The device must have been created with
+    /// [`DeviceFlags::UserRecovery`](crate::control::DeviceFlags::UserRecovery) and
+    /// be quiesced. Its queues are served by `target` once the recovery completes.
+    /// # Errors
+    ///
+    /// If the recovery fails the device is left untouched, so it can be recovered again.
+    pub fn recover(dev_id: u32, target: T) -> Result<Self> {
+        let server = Server::new(target);
+        let mut ctrl = server.ctrl()?;
+
+        let (info, queues) = reattach(&mut ctrl, dev_id, &server)?;
+
+        Ok(Self {
+            ctrl,
+            info,
+            server,
+            queues: Some(queues),
+        })
+    }
+
+    /// Device information, as returned by the kernel driver once the device was started
+    #[must_use]
+    pub const fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// Device id, the block device is /dev/ublkbN
+    #[must_use]
+    pub const fn id(&self) -> u32 {
+        self.info.dev_id
+    }
+
+    /// The target serving the device
+    #[must_use]
+    pub const fn target(&self) -> &Arc<T> {
+        &self.server.target
+    }
+
+    /// The device's control object
+    pub fn ctrl(&mut self) -> &mut UblkCtrl {
+        &mut self.ctrl
+    }
+
+    /// Wait until the device is stopped, e.g., by `ublkctl rm`
+    /// # Errors
+    ///
+    /// Returns the first error reported by a queue thread.
+    pub fn wait(&mut self) -> Result<()> {
+        match self.queues.take() {
+            Some(queues) => queues.join(),
+            None => Ok(()),
+        }
+    }
+
+    /// Resize the live device to `sectors`
+    ///
+    /// The target's backing storage is resized first, see [`BlockTarget::resize()`],
+    /// then the new capacity is set. The device must be created with
+    /// [`DeviceFlags::UpdateSize`](crate::control::DeviceFlags::UpdateSize).
+    /// # Errors
+    ///
+    pub fn resize(&mut self, sectors: u64) -> Result<()> {
+        self.server.target.resize(sectors)?;
+        self.ctrl.update_size(self.info.dev_id, sectors)
+    }
+
+    /// Quiesce the device, and wait for its queues to finish
+    ///
+    /// The in-flight I/O is drained, waiting at most `timeout` (forever if `None`),
+    /// the new I/O is held until the device is resumed, see [`resume()`](Self::resume).
+    /// The device must be created with
+    /// [`DeviceFlags::Quiesce`](crate::control::DeviceFlags::Quiesce).
+    /// # Errors
+    ///
+    pub fn quiesce(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.ctrl.quiesce_device(self.info.dev_id, timeout)?;
+        self.wait()?;
+        self.info = self.ctrl.get_device_info(self.info.dev_id)?;
+        Ok(())
+    }
+
+    /// Resume a quiesced device, its queues are served again by the target
+    /// # Errors
+    ///
+    pub fn resume(&mut self) -> Result<()> {
+        let (info, queues) = reattach(&mut self.ctrl, self.info.dev_id, &self.server)?;
+        self.info = info;
+        self.queues = Some(queues);
+        Ok(())
+    }
+
+    /// Stop the device and wait for its queues to finish
+    /// # Errors
+    ///
+    pub fn stop(&mut self) -> Result<()> {
+        self.ctrl.stop_device(self.info.dev_id)?;
+        self.wait()
+    }
+}
+
+impl<T> Drop for UblkDevice<T> {
+    fn drop(&mut self) {
+        let _ = self.ctrl.stop_device(self.info.dev_id);
+        if let Some(queues) = self.queues.take() {
+            let _ = queues.join();
+        }
+        let _ = self.ctrl.delete_device(self.info.dev_id);
+    }
+}
+
+/// Builder to create and start a [`UblkDevice`]
+///
+/// It adds the device, sets its parameters, spawns one thread per hardware
+/// queue pinned to the queue's cpu affinity, and starts the device once every
+/// queue is waiting for I/O requests.
+pub struct DeviceBuilder<T> {
+    options: DeviceOptions,
+    params: DeviceParams,
+    server: Server<T>,
+}
+
+impl<T: BlockTarget + 'static> DeviceBuilder<T> {
+    /// Device builder constructor
+    #[must_use]
+    pub fn new(target: T) -> Self {
+        Self {
+            options: DeviceOptions::new(),
+            params: DeviceParams::default(),
+            server: Server::new(target),
+        }
+    }
+
+    /// Sets the [`DeviceOptions`] used to add the device
+    #[must_use]
+    pub fn options(mut self, options: DeviceOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets the [`DeviceParams`], the kernel driver refuses to start a device
+    /// without its basic parameters
+    #[must_use]
+    pub fn params(mut self, params: DeviceParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Sets the pool of the I/O buffers of each queue, created by `new_pool(q_id)`,
+    /// see [`TargetDriver::buffer_pool()`]
+    #[must_use]
+    pub fn buffer_pool<P, F>(mut self, new_pool: F) -> Self
+    where
+        P: BufferPool + 'static,
+        F: Fn(u16) -> P + Send + Sync + 'static,
+    {
+        self.server.new_pool = Some(Arc::new(move |q_id| Box::new(new_pool(q_id))));
+        self
+    }
+
+    /// Creates the device with the emulated kernel driver `transport` instead of
+    /// the ublk module, its queues are served through the emulated char device,
+    /// see [`MockTransport::char_device()`]
+    #[cfg(any(test, feature = "mock"))]
+    #[must_use]
+    pub fn mock(mut self, transport: MockTransport) -> Self {
+        self.server.mock = Some(transport);
+        self
+    }
+
+    /// Add and start the device
+    ///
+    /// The device is deleted if any step fails.
+    /// # Errors
+    ///
+    pub fn build(self) -> Result<UblkDevice<T>> {
+        let mut ctrl = self.server.ctrl()?;
+        let info = ctrl.add_device(&self.options)?;
+
+        let mut dev = UblkDevice {
+            ctrl,
+            info,
+            server: self.server,
+            queues: None,
+        };
+
+        dev.ctrl.set_device_parameters(info.dev_id, &self.params)?;
+
+        dev.queues = Some(dev.server.spawn_queues(&mut dev.ctrl, info)?);
+
+        dev.ctrl
+            .start_device(info.dev_id, u64::from(process::id()))?;
+        dev.info = dev.ctrl.get_device_info(info.dev_id)?;
+
+        Ok(dev)
+    }
+}
+
+// What serves the device's queues
+struct Server<T> {
+    target: Arc<T>,
+    new_pool: Option<NewBufferPool>,
+    // the emulated kernel driver, instead of the ublk module
+    #[cfg(any(test, feature = "mock"))]
+    mock: Option<MockTransport>,
+}
+
+impl<T: BlockTarget + 'static> Server<T> {
+    fn new(target: T) -> Self {
+        Self {
+            target: Arc::new(target),
+            new_pool: None,
+            #[cfg(any(test, feature = "mock"))]
+            mock: None,
+        }
+    }
+
+    // Control object of the kernel driver serving the device
+    fn ctrl(&self) -> Result<UblkCtrl> {
+        #[cfg(any(test, feature = "mock"))]
+        if let Some(mock) = &self.mock {
+            return Ok(UblkCtrl::with_transport(mock.clone()));
+        }
+        UblkCtrl::new()
+    }
+
+    // Spawns the threads serving the device's queues, each one pinned to its queue affinity
+    fn spawn_queues(&self, ctrl: &mut UblkCtrl, info: DeviceInfo) -> Result<QueueThreads> {
+        let affinity = ctrl.get_all_queues_affinity(info.dev_id, info.nr_hw_queues)?;
+
+        let driver = TargetDriver::new(info, Arc::clone(&self.target))
+            .affinity(affinity)
+            .new_buffer_pool(self.new_pool.clone());
+
+        // As opening a missing /dev/ublkcN, e.g., of an AutoBufReg device
+        #[cfg(any(test, feature = "mock"))]
+        let driver = match self.mock.as_ref() {
+            Some(mock) => match mock.char_device(info.dev_id) {
+                Some(cdev) => driver.mock(cdev),
+                None => return Err(io::Error::from_raw_os_error(libc::ENOENT).into()),
+            },
+            None => driver,
+        };
+
+        driver.spawn()
+    }
+}
+
+// Attaches the target to the queues of a quiesced device, as in the user recovery
+fn reattach<T: BlockTarget + 'static>(
+    ctrl: &mut UblkCtrl,
+    dev_id: u32,
+    server: &Server<T>,
+) -> Result<(DeviceInfo, QueueThreads)> {
+    let info = ctrl.get_device_info(dev_id)?;
+    if info.state != DeviceState::Quiesced {
+        return Err(Error::NotQuiesced(dev_id));
+    }
+
+    ctrl.start_user_recovery(dev_id)?;
+
+    let queues = server.spawn_queues(ctrl, info)?;
+
+    ctrl.end_user_recovery(dev_id, u64::from(process::id()))?;
+    let info = ctrl.get_device_info(dev_id)?;
+
+    Ok((info, queues))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::DeviceFlags;
+    use crate::queue::{BufferPool, MockCharDevice, MockIo};
+    use std::io;
+    use std::sync::Mutex;
+
+    // In-memory disk, resized on demand
+    struct RamTarget(Mutex<Vec<u8>>);
+
+    impl RamTarget {
+        fn new() -> Self {
+            Self(Mutex::new(vec![0; 1 << 20]))
+        }
+    }
+
+    impl BlockTarget for RamTarget {
+        fn read(&self, sector: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let offset = (sector as usize) << 9;
+            buf.copy_from_slice(&self.0.lock().unwrap()[offset..offset + buf.len()]);
+            Ok(buf.len())
+        }
+
+        fn write(&self, sector: u64, buf: &[u8]) -> io::Result<usize> {
+            let offset = (sector as usize) << 9;
+            self.0.lock().unwrap()[offset..offset + buf.len()].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn resize(&self, sectors: u64) -> io::Result<()> {
+            self.0.lock().unwrap().resize((sectors as usize) << 9, 0);
+            Ok(())
+        }
+    }
+
+    fn params() -> DeviceParams {
+        DeviceParams {
+            logical_bs_shift: 9,
+            physical_bs_shift: 12,
+            io_opt_shift: 12,
+            io_min_shift: 9,
+            max_sectors: 128,
+            dev_sectors: 2048,
+            ..DeviceParams::default()
+        }
+    }
+
+    fn builder(mock: &MockTransport, flags: DeviceFlags) -> DeviceBuilder<RamTarget> {
+        UblkDevice::builder(RamTarget::new())
+            .options(
+                DeviceOptions::new()
+                    .nr_hw_queues(2)
+                    .queue_depth(4)
+                    .flags(flags),
+            )
+            .params(params())
+            .mock(mock.clone())
+    }
+
+    fn exec(cdev: &MockCharDevice, q_id: u16, io: MockIo) -> i32 {
+        let id = cdev.submit(q_id, io).unwrap();
+        let completion = cdev.wait_completion().unwrap();
+        assert_eq!(completion.id, id);
+        completion.result
+    }
+
+    #[test]
+    fn build_and_drop() {
+        let mock = MockTransport::default();
+
+        let dev = builder(&mock, DeviceFlags::empty()).build().unwrap();
+        assert_eq!(dev.info().state, DeviceState::Live);
+        assert_eq!(dev.info().srv_pid, process::id() as i32);
+
+        let cdev = mock.char_device(dev.id()).unwrap();
+        assert_eq!(exec(&cdev, 1, MockIo::write(0, vec![0x5a; 4096])), 4096);
+        assert_eq!(dev.target().0.lock().unwrap()[..4096], [0x5a; 4096]);
+
+        // The device is stopped and deleted
+        drop(dev);
+        assert!(mock.device_ids().is_empty());
+        let err = cdev.submit(0, MockIo::flush()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENODEV));
+    }
+
+    #[test]
+    fn stop() {
+        let mock = MockTransport::default();
+
+        let mut dev = builder(&mock, DeviceFlags::empty()).build().unwrap();
+        dev.stop().unwrap();
+        assert_eq!(
+            mock.device_info(dev.id()).map(|info| info.state),
+            Some(DeviceState::Dead)
+        );
+    }
+
+    struct VecPool;
+
+    impl BufferPool for VecPool {
+        fn alloc(&mut self, len: usize) -> Vec<u8> {
+            vec![0; len]
+        }
+    }
+
+    #[test]
+    fn build_cleanup() {
+        let mock = MockTransport::default();
+
+        // The kernel driver rejects the parameters
+        let res = builder(&mock, DeviceFlags::empty())
+            .params(DeviceParams::default())
+            .build();
+        assert!(res.is_err());
+        assert!(mock.device_ids().is_empty());
+
+        // A queue fails to set up
+        let res = builder(&mock, DeviceFlags::empty())
+            .buffer_pool(|q_id| {
+                assert_ne!(q_id, 1, "no buffer pool for the queue 1");
+                VecPool
+            })
+            .build();
+        assert!(matches!(res, Err(Error::QueuePanicked { q_id: 1, .. })));
+        assert!(mock.device_ids().is_empty());
+    }
+
+    #[test]
+    fn quiesce_and_resume() {
+        let mock = MockTransport::default();
+        let flags = DeviceFlags::Quiesce | DeviceFlags::UserRecovery;
+
+        let mut dev = builder(&mock, flags).build().unwrap();
+        let cdev = mock.char_device(dev.id()).unwrap();
+
+        dev.quiesce(None).unwrap();
+        assert_eq!(dev.info().state, DeviceState::Quiesced);
+
+        // The requests are held until the device is resumed
+        let id = cdev.submit(0, MockIo::write(0, vec![0xa5; 4096])).unwrap();
+        assert!(!cdev.is_queue_ready(0));
+
+        dev.resume().unwrap();
+        assert_eq!(dev.info().state, DeviceState::Live);
+        let completion = cdev.wait_completion().unwrap();
+        assert_eq!((completion.id, completion.result), (id, 4096));
+        assert_eq!(exec(&cdev, 1, MockIo::read(0, 8)), 4096);
+
+        // Only quiesced devices can be resumed
+        assert!(matches!(dev.resume(), Err(Error::NotQuiesced(_))));
+    }
+
+    #[test]
+    fn resize() {
+        let mock = MockTransport::default();
+
+        let mut dev = builder(&mock, DeviceFlags::UpdateSize).build().unwrap();
+        dev.resize(4096).unwrap();
+        assert_eq!(dev.target().0.lock().unwrap().len(), 4096 << 9);
+        let id = dev.id();
+        assert_eq!(
+            dev.ctrl().get_device_parameters(id).unwrap().dev_sectors,
+            4096
+        );
+
+        // The kernel driver can't change the capacity without UpdateSize
+        let mut dev = builder(&mock, DeviceFlags::empty()).build().unwrap();
+        assert!(matches!(
+            dev.resize(4096),
+            Err(Error::OperationNotSupported { .. })
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 // SPDX-License-Identifier: MIT
 
+use crate::control::{CtrlOp, DeviceFlags, UblkCtrl};
 use std::io;
 use thiserror::Error;
 #[derive(Error, Debug)]
@@ -12,6 +13,81 @@ pub enum Error {
         #[from]
         source: io::Error,
     },
+
+    #[error("Invalid queue {q_id} for device {dev_id}")]
+    InvalidQueue { dev_id: u32, q_id: u16 },
+
+    #[error("Device {0} is not quiesced")]
+    NotQuiesced(u32),
+
+    #[error("Thread of queue {q_id} of device {dev_id} panicked")]
+    QueuePanicked { dev_id: u32, q_id: u16 },
+
+    #[error("Unsupported features: {0:?}")]
+    UnsupportedFeatures(DeviceFlags),
+
+    #[error("Invalid device parameters: {0}")]
+    InvalidParams(String),
+
+    #[error("ublk module not loaded, {} not found", UblkCtrl::CTRL_DEV_PATH)]
+    ModuleNotLoaded,
+
+    #[error("Device {dev_id} not found ({op:?})")]
+    DeviceNotFound { dev_id: u32, op: CtrlOp },
+
+    #[error("Device {dev_id} is busy ({op:?})")]
+    DeviceBusy { dev_id: u32, op: CtrlOp },
+
+    #[error("Device {dev_id} already exists ({op:?})")]
+    AlreadyExists { dev_id: u32, op: CtrlOp },
+
+    #[error("Operation not supported on device {dev_id} ({op:?})")]
+    OperationNotSupported { dev_id: u32, op: CtrlOp },
+
+    // `errno` is either EPERM or EACCES
+    #[error("Permission denied on device {dev_id} ({op:?})")]
+    PermissionDenied { dev_id: u32, op: CtrlOp, errno: i32 },
+
+    #[error("Control command {op:?} failed on device {dev_id}: {source}")]
+    Ctrl {
+        dev_id: u32,
+        op: CtrlOp,
+        source: io::Error,
+    },
+}
+
+impl Error {
+    // Error of the control command `op` on the device `dev_id`, failed with `errno`
+    pub(crate) fn ctrl(op: CtrlOp, dev_id: u32, errno: i32) -> Self {
+        match errno {
+            libc::ENODEV => Self::DeviceNotFound { dev_id, op },
+            libc::EBUSY => Self::DeviceBusy { dev_id, op },
+            libc::EEXIST => Self::AlreadyExists { dev_id, op },
+            // ENOTSUPP (524) is kernel internal, but it may leak to userspace
+            libc::EOPNOTSUPP | 524 => Self::OperationNotSupported { dev_id, op },
+            libc::EPERM | libc::EACCES => Self::PermissionDenied { dev_id, op, errno },
+            _ => Self::Ctrl {
+                dev_id,
+                op,
+                source: io::Error::from_raw_os_error(errno),
+            },
+        }
+    }
+
+    /// Returns the errno of the failed system call or control command, if any
+    #[must_use]
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            Self::Io { source } | Self::Ctrl { source, .. } => source.raw_os_error(),
+            Self::DeviceNotFound { .. } => Some(libc::ENODEV),
+            Self::DeviceBusy { .. } => Some(libc::EBUSY),
+            Self::AlreadyExists { .. } => Some(libc::EEXIST),
+            Self::OperationNotSupported { .. } => Some(libc::EOPNOTSUPP),
+            Self::PermissionDenied { errno, .. } => Some(*errno),
+            Self::ModuleNotLoaded => Some(libc::ENOENT),
+            _ => None,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
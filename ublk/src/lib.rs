@@ -6,6 +6,12 @@
 //! block devices.
 //!
 //! ublk aims to be minimal and misuse-resistant.
+//!
+//! With the `serde` feature, the device information, options and parameters
+//! implement `Serialize` and `Deserialize`, the flags as lists of names.
+//!
+//! With the `mock` feature, the kernel driver can be emulated in-process to
+//! test the control paths, the queues and the devices, see `control::MockTransport`.
 
 #[deny(unsafe_op_in_unsafe_fn)]
 #[warn(rustdoc::missing_crate_level_docs, missing_docs)]
@@ -18,6 +24,15 @@
 /// It contains the control paths
 pub mod control;
 
+/// It contains the device lifecycle management
+pub mod device;
+
+/// It contains the data path
+pub mod queue;
+
+/// It contains the target (backend) interface
+pub mod target;
+
 /// Library errors
 pub mod error;
 pub use error::{Error, Result};
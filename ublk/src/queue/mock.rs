@@ -0,0 +1,1128 @@
+// SPDX-License-Identifier: MIT
+
+use crate::control::{DeviceFlags, DeviceInfo};
+use crate::error::{Error, Result};
+use crate::queue::{sys, IoFlags, IoOp};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::{io, mem, ptr, slice};
+
+/// Mock of the kernel side of /dev/ublkcN
+///
+/// It plays the kernel driver for the queues created with
+/// [`UblkQueue::with_mock()`](crate::queue::UblkQueue::with_mock): each request
+/// submitted with [`submit()`](Self::submit) is written to the descriptor of a
+/// tag whose fetch command is waiting, its data is copied to/from the queue's
+/// I/O buffer, or through the char device (an in-memory file) on
+/// [`DeviceFlags::UserCopy`] devices, and the result committed by the queue is
+/// returned by [`wait_completion()`](Self::wait_completion).
+///
+/// The commands are checked as the kernel driver does, an invalid one (e.g.,
+/// the commit of a tag without request) completes with `-EINVAL`.
+/// [`DeviceFlags::AutoBufReg`] devices are not supported.
+///
+/// Clones share the same device, it's aborted when the last one is dropped.
+#[derive(Debug)]
+pub struct MockCharDevice {
+    shared: Arc<Shared>,
+}
+
+/// I/O request submitted to a [`MockCharDevice`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockIo {
+    /// Operation
+    pub op: IoOp,
+    /// Operation flags
+    pub flags: IoFlags,
+    /// Start sector
+    pub start_sector: u64,
+    /// Number of sectors, or number of zones to report for [`IoOp::ReportZones`]
+    pub nr_sectors: u32,
+    /// Data to write, only for [`IoOp::Write`] and [`IoOp::ZoneAppend`]
+    pub data: Vec<u8>,
+}
+
+/// Completion of a request submitted to a [`MockCharDevice`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockCompletion {
+    /// Request id, as returned by [`MockCharDevice::submit()`]
+    pub id: u64,
+    /// Queue id
+    pub q_id: u16,
+    /// Request tag, `None` if the request failed before reaching the queue
+    pub tag: Option<u16>,
+    /// Result committed by the queue, or the negative errno of the failure
+    pub result: i32,
+    /// Data read, only for [`IoOp::Read`] and [`IoOp::ReportZones`]
+    pub data: Vec<u8>,
+    /// First sector written by a [`IoOp::ZoneAppend`] request,
+    /// only on [`DeviceFlags::UserCopy`] devices
+    pub zone_append_lba: u64,
+}
+
+#[derive(Debug)]
+struct Shared {
+    info: DeviceInfo,
+    // in-memory char device, it holds the I/O descriptors and the user copy data
+    cdev: File,
+    state: Mutex<State>,
+    // notified when a command or a request completes
+    cond: Condvar,
+}
+
+#[derive(Debug)]
+struct State {
+    queues: Vec<QueueState>,
+    completions: VecDeque<MockCompletion>,
+    next_id: u64,
+    // submitted requests, not completed yet
+    nr_pending: usize,
+    // MockCharDevice clones
+    nr_handles: usize,
+    aborted: bool,
+    // the fetch commands are aborted, the requests held
+    quiesced: bool,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    attached: bool,
+    tags: Vec<TagState>,
+    // requests waiting for a fetch command
+    backlog: VecDeque<(u64, MockIo)>,
+    // completed commands: user data and result
+    cqes: VecDeque<(u64, i32)>,
+}
+
+#[derive(Debug)]
+enum TagState {
+    // No command
+    Idle,
+    // Fetch command waiting for a request
+    Fetching { user_data: u64, addr: u64 },
+    // Request handed to the queue, waiting for its commit
+    Busy { id: u64, io: MockIo },
+    // Write request waiting for its buffer (NEED_GET_DATA)
+    NeedData { id: u64, io: MockIo },
+}
+
+// Command ring of a queue attached to a mock char device, the commands are
+// handled on submission
+pub(crate) struct MockRing {
+    shared: Arc<Shared>,
+    q_id: u16,
+    sq: Vec<(sys::IoCmdOp, sys::IoCmd, u64)>,
+}
+
+impl MockCharDevice {
+    /// Mock of the char device of the device described by `info`
+    /// # Errors
+    ///
+    /// Returns [`Error::UnsupportedFeatures`] on [`DeviceFlags::AutoBufReg`] devices,
+    /// [`Error::InvalidParams`] if the device has no queues or an invalid queue depth.
+    pub fn new(info: &DeviceInfo) -> Result<Self> {
+        if info.flags.contains(DeviceFlags::AutoBufReg) {
+            return Err(Error::UnsupportedFeatures(DeviceFlags::AutoBufReg));
+        }
+        if info.nr_hw_queues == 0 {
+            return Err(Error::InvalidParams("no hardware queues".to_string()));
+        }
+        if info.queue_depth == 0 || usize::from(info.queue_depth) > sys::MAX_QUEUE_DEPTH {
+            return Err(Error::InvalidParams(format!(
+                "queue depth {} out of range",
+                info.queue_depth
+            )));
+        }
+
+        // SAFETY: the name is NUL terminated.
+        let fd = unsafe { libc::memfd_create(c"ublkc-mock".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // SAFETY: `fd` was just created, nothing else owns it.
+        let cdev = unsafe { File::from_raw_fd(fd) };
+
+        // The descriptors areas are mapped, the user copy data is written
+        // past them and extends the file.
+        cdev.set_len(sys::descs_offset(info.nr_hw_queues) as u64)?;
+
+        let queues = (0..info.nr_hw_queues)
+            .map(|_| QueueState {
+                attached: false,
+                tags: (0..info.queue_depth).map(|_| TagState::Idle).collect(),
+                backlog: VecDeque::new(),
+                cqes: VecDeque::new(),
+            })
+            .collect();
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                info: *info,
+                cdev,
+                state: Mutex::new(State {
+                    queues,
+                    completions: VecDeque::new(),
+                    next_id: 0,
+                    nr_pending: 0,
+                    nr_handles: 1,
+                    aborted: false,
+                    quiesced: false,
+                }),
+                cond: Condvar::new(),
+            }),
+        })
+    }
+
+    /// Information of the emulated device
+    #[must_use]
+    pub fn info(&self) -> &DeviceInfo {
+        &self.shared.info
+    }
+
+    /// Returns `true` if every tag of the queue `q_id` has a command in flight
+    ///
+    /// As the kernel driver, the mock doesn't need the queue to be ready
+    /// to accept requests, they are handed to the tags as they are fetched.
+    #[must_use]
+    pub fn is_queue_ready(&self, q_id: u16) -> bool {
+        let state = self.shared.lock();
+        state.queues.get(usize::from(q_id)).is_some_and(|queue| {
+            queue.attached && queue.tags.iter().all(|tag| !matches!(tag, TagState::Idle))
+        })
+    }
+
+    /// Submits `io` to the queue `q_id`, returns the request id
+    ///
+    /// The requests are handed to the queue in submission order, as soon as
+    /// one of its tags is fetched.
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidQueue`] if the queue doesn't exist, `EINVAL` if
+    /// the request length doesn't match its data or exceeds the device's
+    /// `max_io_buf_bytes`, `ENODEV` if the device is aborted.
+    pub fn submit(&self, q_id: u16, io: MockIo) -> Result<u64> {
+        let info = &self.shared.info;
+        if q_id >= info.nr_hw_queues {
+            return Err(Error::InvalidQueue {
+                dev_id: info.dev_id,
+                q_id,
+            });
+        }
+
+        let len = io.data_len();
+        let data_len = if io.has_data_in() { len } else { 0 };
+        if len > info.max_io_buf_bytes as usize || io.data.len() != data_len {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
+        }
+
+        let mut state = self.shared.lock();
+        if state.aborted {
+            return Err(io::Error::from_raw_os_error(libc::ENODEV).into());
+        }
+
+        let id = state.next_id;
+        state.next_id += 1;
+        state.nr_pending += 1;
+        state.queues[usize::from(q_id)].backlog.push_back((id, io));
+
+        self.shared.dispatch(&mut state, q_id);
+        self.shared.cond.notify_all();
+        Ok(id)
+    }
+
+    /// Waits for the completion of a submitted request, in completion order
+    ///
+    /// Returns `None` if there are no pending requests.
+    pub fn wait_completion(&self) -> Option<MockCompletion> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(completion) = state.completions.pop_front() {
+                return Some(completion);
+            }
+            if state.nr_pending == 0 {
+                return None;
+            }
+            state = self.shared.wait(state);
+        }
+    }
+
+    /// Aborts the device, as the kernel driver does when the device is stopped
+    ///
+    /// The commands in flight complete with `-ENODEV`, so the queues stop, and
+    /// the pending requests fail with `-EIO`. No request can be submitted anymore.
+    pub fn abort(&self) {
+        let mut state = self.shared.lock();
+        self.shared.abort(&mut state);
+        self.shared.cond.notify_all();
+    }
+
+    // Quiesces the device, as the kernel driver does on QUIESCE_DEV: the
+    // fetch commands are aborted once their requests are committed, so the
+    // queues stop, and the new requests are held until `resume()`
+    pub(crate) fn quiesce(&self) {
+        let mut state = self.shared.lock();
+        state.quiesced = true;
+        for queue in &mut state.queues {
+            for slot in &mut queue.tags {
+                if let TagState::Fetching { user_data, .. } = *slot {
+                    *slot = TagState::Idle;
+                    queue.cqes.push_back((user_data, sys::IO_RES_ABORT));
+                }
+            }
+        }
+        self.shared.cond.notify_all();
+    }
+
+    // Lets the queues fetch requests again, as the user recovery does
+    pub(crate) fn resume(&self) {
+        self.shared.lock().quiesced = false;
+    }
+
+    // Attaches a queue, as opening /dev/ublkcN does: returns its command
+    // ring and char device
+    pub(crate) fn attach(&self, q_id: u16) -> Result<(MockRing, File)> {
+        let info = &self.shared.info;
+        if q_id >= info.nr_hw_queues {
+            return Err(Error::InvalidQueue {
+                dev_id: info.dev_id,
+                q_id,
+            });
+        }
+
+        let cdev = self.shared.cdev.try_clone()?;
+
+        let mut state = self.shared.lock();
+        let queue = &mut state.queues[usize::from(q_id)];
+        if queue.attached {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY).into());
+        }
+        queue.attached = true;
+
+        let ring = MockRing {
+            shared: Arc::clone(&self.shared),
+            q_id,
+            sq: Vec::new(),
+        };
+        Ok((ring, cdev))
+    }
+}
+
+impl Clone for MockCharDevice {
+    fn clone(&self) -> Self {
+        self.shared.lock().nr_handles += 1;
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl Drop for MockCharDevice {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.nr_handles -= 1;
+        if state.nr_handles == 0 {
+            self.shared.abort(&mut state);
+            self.shared.cond.notify_all();
+        }
+    }
+}
+
+impl MockIo {
+    /// Read of `nr_sectors` sectors starting at `start_sector`
+    #[must_use]
+    pub const fn read(start_sector: u64, nr_sectors: u32) -> Self {
+        Self::new(IoOp::Read, start_sector, nr_sectors)
+    }
+
+    /// Write of `data` starting at `start_sector`,
+    /// its length must be a multiple of the sector size
+    #[must_use]
+    pub fn write(start_sector: u64, data: Vec<u8>) -> Self {
+        Self {
+            nr_sectors: (data.len() >> sys::SECTOR_SHIFT) as u32,
+            data,
+            ..Self::new(IoOp::Write, start_sector, 0)
+        }
+    }
+
+    /// Flush of the volatile cache
+    #[must_use]
+    pub const fn flush() -> Self {
+        Self::new(IoOp::Flush, 0, 0)
+    }
+
+    /// Discard of `nr_sectors` sectors starting at `start_sector`
+    #[must_use]
+    pub const fn discard(start_sector: u64, nr_sectors: u32) -> Self {
+        Self::new(IoOp::Discard, start_sector, nr_sectors)
+    }
+
+    /// Request without data
+    #[must_use]
+    pub const fn new(op: IoOp, start_sector: u64, nr_sectors: u32) -> Self {
+        Self {
+            op,
+            flags: IoFlags::empty(),
+            start_sector,
+            nr_sectors,
+            data: Vec::new(),
+        }
+    }
+
+    /// Sets the operation flags
+    #[must_use]
+    pub const fn flags(mut self, flags: IoFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    // Length of the request data, as in the queue's I/O buffer
+    const fn data_len(&self) -> usize {
+        match self.op {
+            IoOp::Read | IoOp::Write | IoOp::ZoneAppend => {
+                (self.nr_sectors as usize) << sys::SECTOR_SHIFT
+            }
+            IoOp::ReportZones => self.nr_sectors as usize * sys::BlkZone::SIZE,
+            _ => 0,
+        }
+    }
+
+    // The data is copied to the queue before handing it the request
+    const fn has_data_in(&self) -> bool {
+        matches!(self.op, IoOp::Write | IoOp::ZoneAppend)
+    }
+
+    // The data is copied from the queue when the request is committed
+    const fn has_data_out(&self) -> bool {
+        matches!(self.op, IoOp::Read | IoOp::ReportZones)
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wait<'a>(&self, guard: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.cond
+            .wait(guard)
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn user_copy(&self) -> bool {
+        self.info.flags.contains(DeviceFlags::UserCopy)
+    }
+
+    // The queue takes the buffers of the write requests on demand
+    fn need_get_data(&self) -> bool {
+        self.info.flags.contains(DeviceFlags::NeedGetData) && !self.user_copy()
+    }
+
+    // Handles a command of the queue `q_id`, it completes unless it's
+    // a fetch waiting for a request
+    fn handle_cmd(
+        &self,
+        state: &mut State,
+        q_id: u16,
+        op: sys::IoCmdOp,
+        cmd: sys::IoCmd,
+        user_data: u64,
+    ) {
+        let res = if state.aborted {
+            Some(sys::IO_RES_ABORT)
+        } else if cmd.q_id() != q_id || cmd.tag() >= self.info.queue_depth {
+            Some(-libc::EINVAL)
+        } else {
+            self.exec_cmd(state, q_id, op, cmd, user_data)
+        };
+
+        if let Some(res) = res {
+            state.queues[usize::from(q_id)]
+                .cqes
+                .push_back((user_data, res));
+        }
+        self.dispatch(state, q_id);
+    }
+
+    fn exec_cmd(
+        &self,
+        state: &mut State,
+        q_id: u16,
+        op: sys::IoCmdOp,
+        cmd: sys::IoCmd,
+        user_data: u64,
+    ) -> Option<i32> {
+        let tag = cmd.tag();
+        let addr = cmd.io_addr();
+        let quiesced = state.quiesced;
+        let slot = &mut state.queues[usize::from(q_id)].tags[usize::from(tag)];
+
+        // Without user copy the buffer address is needed, except with
+        // NEED_GET_DATA where it's set later for write requests
+        let (next, res, completion) = match (op, mem::replace(slot, TagState::Idle)) {
+            (sys::IoCmdOp::FetchReq, TagState::Idle) => {
+                if addr == 0 && !self.user_copy() && !self.need_get_data() {
+                    (TagState::Idle, Some(-libc::EINVAL), None)
+                } else if quiesced {
+                    (TagState::Idle, Some(sys::IO_RES_ABORT), None)
+                } else {
+                    (TagState::Fetching { user_data, addr }, None, None)
+                }
+            }
+            (sys::IoCmdOp::CommitAndFetchReq, TagState::Busy { id, io }) => {
+                if addr == 0 && !self.user_copy() && (!self.need_get_data() || io.op == IoOp::Read)
+                {
+                    (TagState::Busy { id, io }, Some(-libc::EINVAL), None)
+                } else {
+                    let completion = self.commit(q_id, tag, id, &io, cmd);
+                    if quiesced {
+                        (TagState::Idle, Some(sys::IO_RES_ABORT), Some(completion))
+                    } else {
+                        (
+                            TagState::Fetching { user_data, addr },
+                            None,
+                            Some(completion),
+                        )
+                    }
+                }
+            }
+            (sys::IoCmdOp::NeedGetData, TagState::NeedData { id, io }) => {
+                if addr == 0 {
+                    (TagState::NeedData { id, io }, Some(-libc::EINVAL), None)
+                } else {
+                    // SAFETY: the queue keeps the buffer alive until the next
+                    // completion of the tag, and it detaches before unmapping it.
+                    unsafe {
+                        ptr::copy_nonoverlapping(io.data.as_ptr(), addr as *mut u8, io.data.len())
+                    };
+                    (TagState::Busy { id, io }, Some(sys::IO_RES_OK), None)
+                }
+            }
+            (_, prev) => (prev, Some(-libc::EINVAL), None),
+        };
+
+        *slot = next;
+        if let Some(completion) = completion {
+            state.complete(completion);
+        }
+        res
+    }
+
+    // Completes the request committed with `cmd`, copying the data read
+    fn commit(&self, q_id: u16, tag: u16, id: u64, io: &MockIo, cmd: sys::IoCmd) -> MockCompletion {
+        let len = io.data_len();
+        let mut result = cmd.io_result();
+        let mut data = Vec::new();
+
+        // The queue can't transfer more than requested
+        if len > 0 && result > 0 && result as usize > len {
+            result = -libc::EIO;
+        }
+
+        if io.has_data_out() && result > 0 {
+            data = vec![0; result as usize];
+            if self.user_copy() {
+                if self
+                    .cdev
+                    .read_exact_at(&mut data, sys::user_copy_offset(q_id, tag))
+                    .is_err()
+                {
+                    data.clear();
+                    result = -libc::EIO;
+                }
+            } else {
+                // SAFETY: the address was checked and the buffer is alive
+                // until the next completion of the tag.
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        cmd.io_addr() as *const u8,
+                        data.as_mut_ptr(),
+                        data.len(),
+                    );
+                }
+            }
+        }
+
+        let zone_append_lba = if io.op == IoOp::ZoneAppend && self.user_copy() {
+            cmd.io_addr()
+        } else {
+            0
+        };
+
+        MockCompletion {
+            id,
+            q_id,
+            tag: Some(tag),
+            result,
+            data,
+            zone_append_lba,
+        }
+    }
+
+    // Hands the waiting requests of the queue `q_id` to its fetched tags
+    fn dispatch(&self, state: &mut State, q_id: u16) {
+        let queue = &mut state.queues[usize::from(q_id)];
+        let mut failed = Vec::new();
+
+        while !queue.backlog.is_empty() {
+            let Some(tag) = queue
+                .tags
+                .iter()
+                .position(|tag| matches!(tag, TagState::Fetching { .. }))
+            else {
+                break;
+            };
+            let Some((id, io)) = queue.backlog.pop_front() else {
+                break;
+            };
+            let TagState::Fetching { user_data, addr } =
+                mem::replace(&mut queue.tags[tag], TagState::Idle)
+            else {
+                unreachable!();
+            };
+
+            let tag = tag as u16;
+            match self.deliver(q_id, tag, addr, &io) {
+                Ok(res) => {
+                    queue.tags[usize::from(tag)] = if res == sys::IO_RES_NEED_GET_DATA {
+                        TagState::NeedData { id, io }
+                    } else {
+                        TagState::Busy { id, io }
+                    };
+                    queue.cqes.push_back((user_data, res));
+                }
+                Err(_) => {
+                    queue.tags[usize::from(tag)] = TagState::Fetching { user_data, addr };
+                    failed.push(id);
+                }
+            }
+        }
+
+        for id in failed {
+            state.fail(id, q_id, None, -libc::EIO);
+        }
+    }
+
+    // Writes the request descriptor and data for the tag, returns the
+    // result of its fetch command
+    fn deliver(&self, q_id: u16, tag: u16, addr: u64, io: &MockIo) -> io::Result<i32> {
+        let desc = sys::IoDesc::new(io.op, io.flags, io.start_sector, io.nr_sectors);
+        let offset =
+            sys::descs_offset(q_id) as u64 + u64::from(tag) * mem::size_of::<sys::IoDesc>() as u64;
+        // SAFETY: `IoDesc` is plain old data without padding.
+        let bytes = unsafe {
+            slice::from_raw_parts(
+                ptr::from_ref(&desc).cast::<u8>(),
+                mem::size_of::<sys::IoDesc>(),
+            )
+        };
+        self.cdev.write_all_at(bytes, offset)?;
+
+        if !io.has_data_in() {
+            return Ok(sys::IO_RES_OK);
+        }
+
+        if self.user_copy() {
+            self.cdev
+                .write_all_at(&io.data, sys::user_copy_offset(q_id, tag))?;
+        } else if self.need_get_data() {
+            return Ok(sys::IO_RES_NEED_GET_DATA);
+        } else {
+            // SAFETY: the fetch command carries the tag's buffer, the queue
+            // keeps it alive while the command is in flight.
+            unsafe { ptr::copy_nonoverlapping(io.data.as_ptr(), addr as *mut u8, io.data.len()) };
+        }
+        Ok(sys::IO_RES_OK)
+    }
+
+    // Completes the commands in flight with IO_RES_ABORT and fails the pending requests
+    fn abort(&self, state: &mut State) {
+        state.aborted = true;
+
+        let mut failed = Vec::new();
+        for (q_id, queue) in state.queues.iter_mut().enumerate() {
+            for (tag, slot) in queue.tags.iter_mut().enumerate() {
+                match mem::replace(slot, TagState::Idle) {
+                    TagState::Fetching { user_data, .. } => {
+                        queue.cqes.push_back((user_data, sys::IO_RES_ABORT));
+                    }
+                    TagState::Busy { id, .. } | TagState::NeedData { id, .. } => {
+                        failed.push((id, q_id as u16, Some(tag as u16)));
+                    }
+                    TagState::Idle => {}
+                }
+            }
+            failed.extend(
+                queue
+                    .backlog
+                    .drain(..)
+                    .map(|(id, _)| (id, q_id as u16, None)),
+            );
+        }
+
+        for (id, q_id, tag) in failed {
+            state.fail(id, q_id, tag, -libc::EIO);
+        }
+    }
+
+    // The queue is gone: its requests in flight are reissued on
+    // UserRecoveryReissue devices, failed otherwise
+    fn detach(&self, q_id: u16) {
+        let mut state = self.lock();
+        let reissue = !state.aborted && self.info.flags.contains(DeviceFlags::UserRecoveryReissue);
+
+        let queue = &mut state.queues[usize::from(q_id)];
+        queue.attached = false;
+        queue.cqes.clear();
+
+        let mut failed = Vec::new();
+        let mut reissued = Vec::new();
+        for (tag, slot) in queue.tags.iter_mut().enumerate() {
+            match mem::replace(slot, TagState::Idle) {
+                TagState::Busy { id, io } | TagState::NeedData { id, io } => {
+                    if reissue {
+                        reissued.push((id, io));
+                    } else {
+                        failed.push((id, tag as u16));
+                    }
+                }
+                TagState::Fetching { .. } | TagState::Idle => {}
+            }
+        }
+        for request in reissued.into_iter().rev() {
+            queue.backlog.push_front(request);
+        }
+
+        for (id, tag) in failed {
+            state.fail(id, q_id, Some(tag), -libc::EIO);
+        }
+        self.cond.notify_all();
+    }
+}
+
+impl State {
+    fn complete(&mut self, completion: MockCompletion) {
+        self.completions.push_back(completion);
+        self.nr_pending -= 1;
+    }
+
+    fn fail(&mut self, id: u64, q_id: u16, tag: Option<u16>, errno: i32) {
+        self.complete(MockCompletion {
+            id,
+            q_id,
+            tag,
+            result: errno,
+            data: Vec::new(),
+            zone_append_lba: 0,
+        });
+    }
+}
+
+impl MockRing {
+    pub(crate) fn push(&mut self, op: sys::IoCmdOp, cmd: sys::IoCmd, user_data: u64) {
+        self.sq.push((op, cmd, user_data));
+    }
+
+    pub(crate) fn submit(&mut self) {
+        if self.sq.is_empty() {
+            return;
+        }
+
+        let mut state = self.shared.lock();
+        for (op, cmd, user_data) in self.sq.drain(..) {
+            self.shared
+                .handle_cmd(&mut state, self.q_id, op, cmd, user_data);
+        }
+        self.shared.cond.notify_all();
+    }
+
+    // Submits the queued commands and waits for at least `want` completions,
+    // they are appended to `cqes` as user data and result
+    pub(crate) fn submit_and_wait(&mut self, want: usize, cqes: &mut Vec<(u64, i32)>) {
+        self.submit();
+
+        let mut state = self.shared.lock();
+        while state.queues[usize::from(self.q_id)].cqes.len() < want {
+            state = self.shared.wait(state);
+        }
+        cqes.extend(state.queues[usize::from(self.q_id)].cqes.drain(..));
+    }
+}
+
+impl Drop for MockRing {
+    fn drop(&mut self) {
+        self.shared.detach(self.q_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::DeviceState;
+    use crate::queue::{IoRequest, UblkQueue, Zone, ZoneCond, ZoneType, FIXED_BUF_USER_DATA};
+    use std::thread::{self, JoinHandle};
+
+    const DISK_SIZE: usize = 1 << 20;
+
+    fn device(flags: DeviceFlags, nr_hw_queues: u16) -> MockCharDevice {
+        MockCharDevice::new(&DeviceInfo {
+            dev_id: 0,
+            srv_pid: -1,
+            active: false,
+            state: DeviceState::Dead,
+            nr_hw_queues,
+            queue_depth: 4,
+            max_io_buf_bytes: 64 << 10,
+            flags,
+            owner_uid: 0,
+            owner_gid: 0,
+        })
+        .unwrap()
+    }
+
+    // Serves the queue `q_id` with `handler` until the device is aborted
+    fn serve<F>(dev: &MockCharDevice, q_id: u16, direct: bool, handler: F) -> JoinHandle<Result<()>>
+    where
+        F: FnMut(IoRequest<'_>) -> i32 + Send + 'static,
+    {
+        let dev = dev.clone();
+        thread::spawn(move || {
+            let mut queue = UblkQueue::with_mock(&dev, q_id)?.direct_copy(direct);
+            // The attached queue doesn't keep the device alive
+            drop(dev);
+            queue.submit_fetch_commands()?;
+            queue.run(handler)
+        })
+    }
+
+    // In-memory disk, as a handler
+    fn ram_disk() -> impl FnMut(IoRequest<'_>) -> i32 + Send + 'static {
+        let mut disk = vec![0_u8; DISK_SIZE];
+        move |req| {
+            let offset = (req.start_sector as usize) << sys::SECTOR_SHIFT;
+            let len = (req.nr_sectors as usize) << sys::SECTOR_SHIFT;
+            let range = &mut disk[offset..offset + len];
+            let res = match (req.op, &req.data) {
+                (IoOp::Read, Some(data)) => data.write_at(range, 0),
+                (IoOp::Read, None) => {
+                    req.buffer.copy_from_slice(range);
+                    Ok(len)
+                }
+                (IoOp::Write, Some(data)) => data.read_at(range, 0),
+                (IoOp::Write, None) => {
+                    range.copy_from_slice(req.buffer);
+                    Ok(len)
+                }
+                (IoOp::Discard, _) => {
+                    range.fill(0);
+                    Ok(0)
+                }
+                (IoOp::Flush, _) => Ok(0),
+                _ => return -libc::EOPNOTSUPP,
+            };
+            res.map_or_else(|err| -err.raw_os_error().unwrap(), |len| len as i32)
+        }
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    // Submits `io` and waits for its completion
+    fn exec(dev: &MockCharDevice, q_id: u16, io: MockIo) -> MockCompletion {
+        let id = dev.submit(q_id, io).unwrap();
+        let completion = dev.wait_completion().unwrap();
+        assert_eq!(completion.id, id);
+        assert_eq!(completion.q_id, q_id);
+        completion
+    }
+
+    fn stop(dev: &MockCharDevice, queue: JoinHandle<Result<()>>) {
+        dev.abort();
+        queue.join().unwrap().unwrap();
+    }
+
+    fn check_io_ops(flags: DeviceFlags, direct: bool) {
+        let dev = device(flags, 1);
+        let queue = serve(&dev, 0, direct, ram_disk());
+
+        let data = pattern(8 << 10, 0x5a);
+        let res = exec(&dev, 0, MockIo::write(16, data.clone()));
+        assert_eq!(res.result, data.len() as i32);
+
+        let res = exec(&dev, 0, MockIo::read(16, 16));
+        assert_eq!(res.result, data.len() as i32);
+        assert_eq!(res.data, data);
+
+        assert_eq!(exec(&dev, 0, MockIo::flush()).result, 0);
+
+        assert_eq!(exec(&dev, 0, MockIo::discard(16, 8)).result, 0);
+        let res = exec(&dev, 0, MockIo::read(16, 16));
+        assert_eq!(res.data[..4096], [0; 4096]);
+        assert_eq!(res.data[4096..], data[4096..]);
+
+        stop(&dev, queue);
+    }
+
+    #[test]
+    fn io_ops() {
+        check_io_ops(DeviceFlags::empty(), false);
+    }
+
+    #[test]
+    fn io_ops_user_copy() {
+        check_io_ops(DeviceFlags::UserCopy, false);
+    }
+
+    #[test]
+    fn io_ops_direct_copy() {
+        check_io_ops(DeviceFlags::UserCopy, true);
+    }
+
+    #[test]
+    fn io_ops_need_get_data() {
+        check_io_ops(DeviceFlags::NeedGetData, false);
+    }
+
+    #[test]
+    fn need_get_data() {
+        let dev = device(DeviceFlags::NeedGetData, 1);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let queue = serve(&dev, 0, false, move |req| {
+            tx.send(req.buffer.to_vec()).unwrap();
+            req.buffer.len() as i32
+        });
+
+        // The write data reaches the queue once it provides a buffer for it
+        let data = pattern(4096, 0x11);
+        for _ in 0..8 {
+            let res = exec(&dev, 0, MockIo::write(0, data.clone()));
+            assert_eq!(res.result, data.len() as i32);
+            assert_eq!(rx.recv().unwrap(), data);
+        }
+
+        // Requests without data don't need a buffer
+        assert_eq!(exec(&dev, 0, MockIo::flush()).result, 0);
+        assert_eq!(rx.recv().unwrap(), Vec::<u8>::new());
+
+        stop(&dev, queue);
+    }
+
+    #[test]
+    fn negative_errno() {
+        let dev = device(DeviceFlags::empty(), 1);
+        let queue = serve(&dev, 0, false, |req| match req.op {
+            IoOp::Read => -libc::EIO,
+            _ => -libc::EOPNOTSUPP,
+        });
+
+        let res = exec(&dev, 0, MockIo::read(0, 8));
+        assert_eq!(res.result, -libc::EIO);
+        assert!(res.data.is_empty());
+        assert_eq!(exec(&dev, 0, MockIo::flush()).result, -libc::EOPNOTSUPP);
+
+        // The queue keeps serving after a failed request
+        assert_eq!(exec(&dev, 0, MockIo::read(0, 8)).result, -libc::EIO);
+
+        stop(&dev, queue);
+    }
+
+    #[test]
+    fn abort_on_shutdown() {
+        let dev = device(DeviceFlags::empty(), 2);
+        let queue = serve(&dev, 0, false, ram_disk());
+
+        assert_eq!(exec(&dev, 0, MockIo::flush()).result, 0);
+
+        // Nobody serves the queue 1
+        let pending = dev.submit(1, MockIo::read(0, 8)).unwrap();
+
+        // The fetch commands complete, so the queue stops
+        stop(&dev, queue);
+
+        let res = dev.wait_completion().unwrap();
+        assert_eq!(res.id, pending);
+        assert_eq!(res.tag, None);
+        assert_eq!(res.result, -libc::EIO);
+        assert!(dev.wait_completion().is_none());
+
+        let err = dev.submit(0, MockIo::flush()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENODEV));
+    }
+
+    #[test]
+    fn abort_on_last_drop() {
+        let dev = device(DeviceFlags::empty(), 1);
+        let queue = serve(&dev, 0, false, ram_disk());
+
+        assert_eq!(exec(&dev, 0, MockIo::flush()).result, 0);
+
+        drop(dev);
+        queue.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn quiesce_and_resume() {
+        let dev = device(DeviceFlags::empty(), 1);
+        let queue = serve(&dev, 0, false, ram_disk());
+        assert_eq!(exec(&dev, 0, MockIo::flush()).result, 0);
+
+        // The queue stops, the requests are held until it's served again
+        dev.quiesce();
+        queue.join().unwrap().unwrap();
+        let id = dev.submit(0, MockIo::flush()).unwrap();
+
+        dev.resume();
+        let queue = serve(&dev, 0, false, ram_disk());
+        let res = dev.wait_completion().unwrap();
+        assert_eq!((res.id, res.result), (id, 0));
+
+        stop(&dev, queue);
+    }
+
+    #[test]
+    fn user_copy_offsets() {
+        let dev = device(DeviceFlags::UserCopy, 2);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut disk = ram_disk();
+        let queue = serve(&dev, 1, true, move |req| {
+            let offset = req.data.as_ref().map(|data| data.file_offset());
+            tx.send((req.tag, offset)).unwrap();
+            disk(req)
+        });
+
+        for (idx, start_sector) in [0, 8, 64, 128].into_iter().enumerate() {
+            let data = pattern(2048, idx as u8);
+            let res = exec(&dev, 1, MockIo::write(start_sector, data.clone()));
+            assert_eq!(res.result, data.len() as i32);
+            let (tag, offset) = rx.recv().unwrap();
+            assert_eq!(res.tag, Some(tag));
+            assert_eq!(offset, Some(sys::user_copy_offset(1, tag)));
+
+            let res = exec(&dev, 1, MockIo::read(start_sector, 4));
+            assert_eq!(res.data, data);
+            let (tag, offset) = rx.recv().unwrap();
+            assert_eq!(offset, Some(sys::user_copy_offset(1, tag)));
+        }
+
+        // Requests without data have no request data
+        assert_eq!(exec(&dev, 1, MockIo::flush()).result, 0);
+        assert_eq!(rx.recv().unwrap().1, None);
+
+        stop(&dev, queue);
+    }
+
+    #[test]
+    fn zone_append() {
+        let dev = device(DeviceFlags::UserCopy | DeviceFlags::Zoned, 1);
+        let (tx, rx) = std::sync::mpsc::channel();
+        // Appends at a write pointer 8 sectors into the zone
+        let queue = serve(&dev, 0, false, move |mut req| {
+            assert_eq!(req.op, IoOp::ZoneAppend);
+            tx.send(req.buffer.to_vec()).unwrap();
+            let lba = req.start_sector + 8;
+            *req.zone_append_lba.take().unwrap() = lba;
+            req.buffer.len() as i32
+        });
+
+        for (idx, zone_start) in [0, 256].into_iter().enumerate() {
+            let data = pattern(4096, idx as u8);
+            let io = MockIo {
+                data: data.clone(),
+                ..MockIo::new(IoOp::ZoneAppend, zone_start, 8)
+            };
+            let res = exec(&dev, 0, io);
+            assert_eq!(res.result, data.len() as i32);
+            assert_eq!(res.zone_append_lba, zone_start + 8);
+            assert_eq!(rx.recv().unwrap(), data);
+        }
+
+        stop(&dev, queue);
+    }
+
+    #[test]
+    fn report_zones() {
+        let zones: Vec<_> = (0..2)
+            .map(|idx| Zone {
+                start: idx * 256,
+                len: 256,
+                wp: idx * 256 + 16,
+                zone_type: ZoneType::SeqWriteRequired,
+                cond: ZoneCond::ImplicitOpen,
+                capacity: 256,
+            })
+            .collect();
+
+        let dev = device(DeviceFlags::UserCopy | DeviceFlags::Zoned, 1);
+        let queue = serve(&dev, 0, false, {
+            let zones = zones.clone();
+            move |mut req| {
+                assert_eq!(req.op, IoOp::ReportZones);
+                // Stale data, overwritten by the zone descriptors
+                req.buffer.fill(0xff);
+                req.fill_zones(&zones)
+            }
+        });
+
+        let mut expected = vec![0; 2 * sys::BlkZone::SIZE];
+        for (zone, desc) in zones
+            .iter()
+            .zip(expected.chunks_exact_mut(sys::BlkZone::SIZE))
+        {
+            sys::BlkZone::from(zone).write_to(desc);
+        }
+
+        // The trailing descriptors are zeroed
+        let res = exec(&dev, 0, MockIo::new(IoOp::ReportZones, 0, 4));
+        assert_eq!(res.result, 4 * sys::BlkZone::SIZE as i32);
+        assert_eq!(res.data.len(), 4 * sys::BlkZone::SIZE);
+        assert_eq!(res.data[..expected.len()], expected);
+        assert!(res.data[expected.len()..].iter().all(|&b| b == 0));
+
+        // The zones that don't fit are left out
+        let res = exec(&dev, 0, MockIo::new(IoOp::ReportZones, 0, 1));
+        assert_eq!(res.result, sys::BlkZone::SIZE as i32);
+        assert_eq!(res.data, expected[..sys::BlkZone::SIZE]);
+
+        stop(&dev, queue);
+    }
+
+    #[test]
+    fn late_fixed_buf_completion() {
+        let dev = device(DeviceFlags::empty(), 1);
+        let queue = {
+            let dev = dev.clone();
+            thread::spawn(move || {
+                let mut queue = UblkQueue::with_mock(&dev, 0)?;
+                drop(dev);
+                queue.submit_fetch_commands()?;
+                // Completion of a fixed buffer I/O its submitter gave up on
+                queue.deferred_cqes.push((FIXED_BUF_USER_DATA, -libc::EIO));
+                queue.run(ram_disk())
+            })
+        };
+
+        assert_eq!(exec(&dev, 0, MockIo::flush()).result, 0);
+        stop(&dev, queue);
+    }
+
+    #[test]
+    fn queue_attached_once() {
+        let dev = device(DeviceFlags::empty(), 1);
+
+        let queue = UblkQueue::with_mock(&dev, 0).unwrap();
+        let err = UblkQueue::with_mock(&dev, 0).err().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+        assert!(matches!(
+            UblkQueue::with_mock(&dev, 1),
+            Err(Error::InvalidQueue { q_id: 1, .. })
+        ));
+
+        // Dropping the queue detaches it
+        drop(queue);
+        UblkQueue::with_mock(&dev, 0).unwrap();
+    }
+}
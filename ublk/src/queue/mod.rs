@@ -0,0 +1,1097 @@
+// SPDX-License-Identifier: MIT
+
+#[cfg(any(test, feature = "mock"))]
+mod mock;
+mod sys;
+
+#[cfg(any(test, feature = "mock"))]
+pub use mock::{MockCharDevice, MockCompletion, MockIo};
+
+use crate::control::{CmdEncoding, DeviceFlags, DeviceInfo};
+use crate::error::{Error, Result};
+use bitflags::bitflags;
+use io_uring::opcode::{ReadFixed, UringCmd16, WriteFixed};
+use io_uring::types::{Fd, Fixed};
+use io_uring::{squeue, IoUring};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd};
+use std::{io, mem, ptr, slice};
+
+/// Queue object
+///
+/// It serves the I/O requests of one of the device's hardware queues: for each
+/// tag it owns one I/O buffer and keeps one uring command in flight against
+/// /dev/ublkcN, when the kernel driver completes it the request is decoded
+/// and handed to the user code, its result is sent back together with the
+/// request to fetch the next I/O for that tag.
+///
+/// On [`DeviceFlags::UserCopy`] devices the queue copies the request data
+/// between the I/O buffer and the kernel driver through /dev/ublkcN, unless
+/// direct copy is enabled, see [`direct_copy()`](Self::direct_copy).
+///
+/// On [`DeviceFlags::NeedGetData`] devices the I/O buffers are not allocated
+/// up front, the queue takes one from its [`BufferPool`] when a request needs
+/// it and gives it back when the kernel driver is done with it.
+///
+/// On [`DeviceFlags::AutoBufReg`] devices the kernel driver registers the
+/// buffer of each read and write request in the queue's io_uring, at the
+/// request's tag index, the handler does I/O on it with [`FixedBuffer`].
+///
+/// All the fetch commands of a queue must be issued from the same thread,
+/// the one that will serve the queue's I/O.
+///
+/// With the `mock` feature, the queue can be served without the kernel driver
+/// by a `MockCharDevice`, see `with_mock()`.
+pub struct UblkQueue {
+    // must be dropped first, so no command references the buffers after unmapping them
+    ring: CmdRing,
+    q_id: u16,
+    depth: u16,
+    encoding: CmdEncoding,
+    user_copy: bool,
+    direct_copy: bool,
+    auto_buf_reg: bool,
+    // with NeedGetData, the buffers are taken from the pool on demand
+    pool: Option<Box<dyn BufferPool>>,
+    tags: Vec<TagState>,
+    // first sector written by each tag's zone append request
+    zone_append_lbas: Vec<u64>,
+    nr_inflight: usize,
+    // completions received while waiting for a fixed buffer I/O: user data and result
+    deferred_cqes: Vec<(u64, i32)>,
+    stopping: bool,
+    bufs: IoBufs,
+    descs: MmapRegion,
+    cdev: File,
+}
+
+impl UblkQueue {
+    /// ublk char device path prefix, the device id must be appended
+    pub const CDEV_PATH_PREFIX: &'static str = "/dev/ublkc";
+
+    /// Sector size in bytes, `start_sector` and `nr_sectors` are expressed in this unit
+    pub const SECTOR_SIZE: u32 = 1 << sys::SECTOR_SHIFT;
+
+    /// Queue constructor
+    ///
+    /// Opens the device's char device and maps the queue's I/O descriptors area,
+    /// no command is sent to the kernel driver until
+    /// [`submit_fetch_commands()`](Self::submit_fetch_commands) is called.
+    /// # Errors
+    ///
+    pub fn new(info: &DeviceInfo, q_id: u16) -> Result<Self> {
+        if q_id >= info.nr_hw_queues {
+            return Err(Error::InvalidQueue {
+                dev_id: info.dev_id,
+                q_id,
+            });
+        }
+
+        let depth = info.queue_depth;
+        let ring = IoUring::new(u32::from(depth))?;
+
+        let cdev = OpenOptions::new().read(true).write(true).open(format!(
+            "{}{}",
+            Self::CDEV_PATH_PREFIX,
+            info.dev_id
+        ))?;
+
+        ring.submitter().register_files(&[cdev.as_raw_fd()])?;
+
+        // One empty fixed buffer slot per tag, filled by the kernel driver
+        // when the request is fetched
+        if info.flags.contains(DeviceFlags::AutoBufReg) {
+            let slots = vec![
+                libc::iovec {
+                    iov_base: ptr::null_mut(),
+                    iov_len: 0,
+                };
+                usize::from(depth)
+            ];
+            ring.submitter().register_buffers(&slots)?;
+        }
+
+        Self::with_ring(info, q_id, CmdRing::Uring(Box::new(ring)), cdev)
+    }
+
+    /// Queue `q_id` of the device emulated by `device`
+    ///
+    /// The queue works as on a real device, but its commands are handled by
+    /// `device` instead of the kernel driver, so it can be tested without the
+    /// ublk module. Dropping the queue detaches it from `device`.
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidQueue`] if the queue doesn't exist,
+    /// `EBUSY` if it's already attached.
+    #[cfg(any(test, feature = "mock"))]
+    pub fn with_mock(device: &MockCharDevice, q_id: u16) -> Result<Self> {
+        let (ring, cdev) = device.attach(q_id)?;
+        Self::with_ring(device.info(), q_id, CmdRing::Mock(ring), cdev)
+    }
+
+    fn with_ring(info: &DeviceInfo, q_id: u16, ring: CmdRing, cdev: File) -> Result<Self> {
+        let depth = info.queue_depth;
+        let auto_buf_reg = info.flags.contains(DeviceFlags::AutoBufReg);
+
+        let page_size = page_size();
+
+        let descs_size =
+            (usize::from(depth) * mem::size_of::<sys::IoDesc>()).next_multiple_of(page_size);
+        let descs = MmapRegion::new(
+            descs_size,
+            libc::PROT_READ,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            cdev.as_raw_fd(),
+            sys::descs_offset(q_id),
+        )?;
+
+        let user_copy = info.flags.contains(DeviceFlags::UserCopy);
+        let need_get_data =
+            info.flags.contains(DeviceFlags::NeedGetData) && !user_copy && !auto_buf_reg;
+
+        let buf_size = (info.max_io_buf_bytes as usize).next_multiple_of(page_size);
+        let region = if need_get_data {
+            None
+        } else {
+            Some(MmapRegion::new(
+                buf_size * usize::from(depth),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )?)
+        };
+
+        let queue = Self {
+            ring,
+            q_id,
+            depth,
+            encoding: if info.flags.contains(DeviceFlags::CmdIoctlEncode) {
+                CmdEncoding::Ioctl
+            } else {
+                CmdEncoding::Legacy
+            },
+            user_copy,
+            direct_copy: false,
+            auto_buf_reg,
+            pool: need_get_data.then(|| Box::new(HeapBufferPool::default()) as Box<dyn BufferPool>),
+            tags: vec![TagState::Idle; usize::from(depth)],
+            zone_append_lbas: vec![0; usize::from(depth)],
+            nr_inflight: 0,
+            deferred_cqes: Vec::new(),
+            stopping: false,
+            bufs: IoBufs {
+                region,
+                pool_bufs: (0..depth).map(|_| None).collect(),
+                buf_size,
+            },
+            descs,
+            cdev,
+        };
+        Ok(queue)
+    }
+
+    /// Enables the direct copy of the request data on [`DeviceFlags::UserCopy`] devices
+    ///
+    /// The data of read and write requests is not copied to/from the queue's
+    /// I/O buffers, instead the handler copies it from/to its own buffers
+    /// with [`IoRequest::data`]. It's ignored on other devices.
+    #[must_use]
+    pub const fn direct_copy(mut self, direct: bool) -> Self {
+        self.direct_copy = direct;
+        self
+    }
+
+    /// Sets the pool of the I/O buffers on [`DeviceFlags::NeedGetData`] devices
+    ///
+    /// By default the buffers are allocated on the heap, and reused once the
+    /// kernel driver is done with them. It's ignored on other devices.
+    #[must_use]
+    pub fn buffer_pool<P: BufferPool + 'static>(mut self, pool: P) -> Self {
+        if self.pool.is_some() {
+            self.pool = Some(Box::new(pool));
+        }
+        self
+    }
+
+    /// Queue id
+    #[must_use]
+    pub const fn id(&self) -> u16 {
+        self.q_id
+    }
+
+    /// Queue depth
+    #[must_use]
+    pub const fn depth(&self) -> u16 {
+        self.depth
+    }
+
+    /// Returns `true` if the kernel driver aborted the queue, e.g., the device is being stopped
+    #[must_use]
+    pub const fn is_stopping(&self) -> bool {
+        self.stopping
+    }
+
+    /// Returns the number of fetch commands waiting for an I/O request
+    #[must_use]
+    pub const fn nr_inflight(&self) -> usize {
+        self.nr_inflight
+    }
+
+    /// Submit a fetch command for every tag of the queue
+    ///
+    /// The device is ready to be started once every queue has submitted its
+    /// fetch commands.
+    /// # Errors
+    ///
+    pub fn submit_fetch_commands(&mut self) -> Result<()> {
+        for tag in 0..self.depth {
+            if self.tags[usize::from(tag)] == TagState::Idle {
+                self.queue_io_cmd(sys::IoCmdOp::FetchReq, tag, 0)?;
+            }
+        }
+
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Wait for I/O requests and handle them
+    ///
+    /// It blocks until at least one command completes, every received I/O request
+    /// is passed to `handler` whose return value is the request result: the number
+    /// of bytes transferred or a negative errno. Returns the number of completed
+    /// commands, zero if there are no commands in flight.
+    /// # Errors
+    ///
+    pub fn process_io<F>(&mut self, mut handler: F) -> Result<usize>
+    where
+        F: FnMut(IoRequest<'_>) -> i32,
+    {
+        if self.nr_inflight == 0 {
+            return Ok(0);
+        }
+
+        // Don't block if completions were received by a fixed buffer I/O
+        let want = usize::from(self.deferred_cqes.is_empty());
+        let mut cqes = mem::take(&mut self.deferred_cqes);
+        self.ring.submit_and_wait(want, &mut cqes)?;
+
+        for &(user_data, res) in &cqes {
+            self.handle_cqe(user_data, res, &mut handler)?;
+        }
+
+        self.ring.submit()?;
+        Ok(cqes.len())
+    }
+
+    /// Serve the queue's I/O requests until the kernel driver aborts the queue
+    ///
+    /// The fetch commands must be already submitted,
+    /// see [`submit_fetch_commands()`](Self::submit_fetch_commands).
+    /// # Errors
+    ///
+    pub fn run<F>(&mut self, mut handler: F) -> Result<()>
+    where
+        F: FnMut(IoRequest<'_>) -> i32,
+    {
+        while self.process_io(&mut handler)? > 0 {}
+        Ok(())
+    }
+
+    fn handle_cqe<F>(&mut self, user_data: u64, res: i32, handler: &mut F) -> Result<()>
+    where
+        F: FnMut(IoRequest<'_>) -> i32,
+    {
+        // A fixed buffer I/O whose submitter gave up waiting on an error
+        if user_data == FIXED_BUF_USER_DATA {
+            return Ok(());
+        }
+
+        let tag = user_data_to_tag(user_data);
+        let state = self.tags[usize::from(tag)];
+        self.tags[usize::from(tag)] = TagState::Idle;
+        self.nr_inflight -= 1;
+
+        // The kernel driver is done with the buffer of the previous request,
+        // unless it just copied the data of this write request into it
+        if state != TagState::GettingData {
+            self.put_pool_buf(tag);
+        }
+
+        match res {
+            sys::IO_RES_OK => {
+                let res = self.serve_request(tag, handler);
+                self.queue_io_cmd(sys::IoCmdOp::CommitAndFetchReq, tag, res)?;
+            }
+            sys::IO_RES_NEED_GET_DATA => match self.get_pool_buf(tag) {
+                Ok(()) => self.queue_io_cmd(sys::IoCmdOp::NeedGetData, tag, 0)?,
+                Err(err) => self.queue_io_cmd(sys::IoCmdOp::CommitAndFetchReq, tag, err)?,
+            },
+            sys::IO_RES_ABORT => {
+                self.stopping = true;
+                self.tags[usize::from(tag)] = TagState::Done;
+            }
+            // The command failed, this tag won't be fetched again
+            _ => self.tags[usize::from(tag)] = TagState::Done,
+        }
+
+        Ok(())
+    }
+
+    // Hands the request to `handler`, with user copy the request data is
+    // copied from/to the kernel driver before/after it
+    fn serve_request<F>(&mut self, tag: u16, handler: &mut F) -> i32
+    where
+        F: FnMut(IoRequest<'_>) -> i32,
+    {
+        let op = self.io_desc(tag).op();
+        self.zone_append_lbas[usize::from(tag)] = 0;
+
+        let user_copy = self.user_copy && !self.is_direct_copy(op);
+
+        if self.pool.is_some() && self.bufs.pool_bufs[usize::from(tag)].is_none() {
+            if let Err(err) = self.get_pool_buf(tag) {
+                return err;
+            }
+        }
+
+        if user_copy && matches!(op, IoOp::Write | IoOp::ZoneAppend) {
+            let len = self.io_buf_len(tag);
+            if let Err(err) = self.cdev.read_exact_at(
+                self.bufs.get_mut(tag, len),
+                sys::user_copy_offset(self.q_id, tag),
+            ) {
+                return -err.raw_os_error().unwrap_or(libc::EIO);
+            }
+        }
+
+        let res = handler(self.io_request(tag));
+
+        if user_copy && res > 0 && matches!(op, IoOp::Read | IoOp::ReportZones) {
+            let len = (res as usize).min(self.io_buf_len(tag));
+            if let Err(err) = self.cdev.write_all_at(
+                self.bufs.get_mut(tag, len),
+                sys::user_copy_offset(self.q_id, tag),
+            ) {
+                return -err.raw_os_error().unwrap_or(libc::EIO);
+            }
+        }
+
+        res
+    }
+
+    // The data of read and write requests is copied by the handler
+    fn is_direct_copy(&self, op: IoOp) -> bool {
+        self.user_copy && self.direct_copy && matches!(op, IoOp::Read | IoOp::Write)
+    }
+
+    // The buffer of read and write requests is registered by the kernel driver
+    fn is_fixed_buf(&self, op: IoOp) -> bool {
+        self.auto_buf_reg && matches!(op, IoOp::Read | IoOp::Write)
+    }
+
+    fn io_request(&mut self, tag: u16) -> IoRequest<'_> {
+        let desc = self.io_desc(tag);
+        let len = (desc.nr_sectors() as usize) << sys::SECTOR_SHIFT;
+
+        let (buffer, data, fixed_buf) = if self.is_direct_copy(desc.op()) {
+            let data = RequestData {
+                cdev: &self.cdev,
+                offset: sys::user_copy_offset(self.q_id, tag),
+                len,
+            };
+            (&mut [][..], Some(data), None)
+        } else if self.is_fixed_buf(desc.op()) {
+            // Mock char devices don't support auto buffer registration
+            let ring = self.ring.as_uring().expect("io_uring command ring");
+            let fixed_buf = FixedBuffer {
+                ring,
+                deferred_cqes: &mut self.deferred_cqes,
+                index: tag,
+                len,
+            };
+            (&mut [][..], None, Some(fixed_buf))
+        } else {
+            let buf_len = self.io_buf_len(tag);
+            (self.bufs.get_mut(tag, buf_len), None, None)
+        };
+
+        let zone_append_lba = match desc.op() {
+            IoOp::ZoneAppend => Some(&mut self.zone_append_lbas[usize::from(tag)]),
+            _ => None,
+        };
+
+        IoRequest {
+            tag,
+            op: desc.op(),
+            flags: desc.flags(),
+            start_sector: desc.start_sector(),
+            nr_sectors: desc.nr_sectors(),
+            buffer,
+            data,
+            fixed_buf,
+            zone_append_lba,
+        }
+    }
+
+    // Length of the request data in the tag's I/O buffer
+    fn io_buf_len(&self, tag: u16) -> usize {
+        let desc = self.io_desc(tag);
+
+        let len = match desc.op() {
+            IoOp::Read | IoOp::Write | IoOp::ZoneAppend => {
+                (desc.nr_sectors() as usize) << sys::SECTOR_SHIFT
+            }
+            IoOp::ReportZones => desc.nr_zones() as usize * sys::BlkZone::SIZE,
+            _ => 0,
+        };
+
+        len.min(self.bufs.buf_size)
+    }
+
+    // Takes a buffer for the tag's request from the pool,
+    // returns the negative errno to commit on failure
+    fn get_pool_buf(&mut self, tag: u16) -> std::result::Result<(), i32> {
+        let len = self.io_buf_len(tag);
+        let Some(pool) = self.pool.as_mut() else {
+            return Ok(());
+        };
+        if len == 0 {
+            return Ok(());
+        }
+
+        let buf = pool.alloc(len);
+        if buf.len() < len {
+            pool.free(buf);
+            return Err(-libc::ENOMEM);
+        }
+
+        self.bufs.pool_bufs[usize::from(tag)] = Some(PoolBuf::from(buf));
+        Ok(())
+    }
+
+    // Gives the tag's buffer back to the pool
+    fn put_pool_buf(&mut self, tag: u16) {
+        if let (Some(pool), Some(buf)) = (
+            self.pool.as_mut(),
+            self.bufs.pool_bufs[usize::from(tag)].take(),
+        ) {
+            pool.free(buf.into());
+        }
+    }
+
+    fn io_desc(&self, tag: u16) -> sys::IoDesc {
+        let descs = self.descs.addr.cast::<sys::IoDesc>();
+        // SAFETY: the descriptors area is mapped for `depth` descriptors and
+        // the kernel driver only updates it while the tag is being fetched.
+        unsafe { ptr::read_volatile(descs.add(usize::from(tag))) }
+    }
+
+    fn queue_io_cmd(&mut self, op: sys::IoCmdOp, tag: u16, result: i32) -> Result<()> {
+        // With user copy or auto buffer registration the buffer address must
+        // not be set, the command carries the zone append result instead
+        // (zero for other requests).
+        let cmd = sys::IoCmd::new(self.q_id, tag).result(result);
+        let cmd = if self.user_copy || self.auto_buf_reg {
+            cmd.zone_append_lba(self.zone_append_lbas[usize::from(tag)])
+        } else {
+            cmd.addr(self.bufs.addr(tag) as u64)
+        };
+
+        match &mut self.ring {
+            CmdRing::Uring(ring) => {
+                let mut sqe = UringCmd16::new(Fixed(0), op.opcode(self.encoding))
+                    .cmd(cmd.into())
+                    .build()
+                    .user_data(tag_to_user_data(tag));
+
+                // The request buffer is registered at the tag's fixed buffer index
+                if self.auto_buf_reg {
+                    sys::set_sqe_addr(&mut sqe, sys::auto_buf_reg_addr(tag));
+                }
+
+                // SAFETY: the command data is copied into the sqe, and the I/O buffer
+                // lives as long as the queue, or until the tag's next completion.
+                unsafe { ring.submission().push(&sqe) }?;
+            }
+            #[cfg(any(test, feature = "mock"))]
+            CmdRing::Mock(ring) => ring.push(op, cmd, tag_to_user_data(tag)),
+        }
+
+        self.tags[usize::from(tag)] = match op {
+            sys::IoCmdOp::NeedGetData => TagState::GettingData,
+            _ => TagState::InFlight,
+        };
+        self.nr_inflight += 1;
+        Ok(())
+    }
+}
+
+/// I/O request received from the kernel driver
+#[derive(Debug)]
+pub struct IoRequest<'a> {
+    /// Request tag, unique among the queue's in-flight requests
+    pub tag: u16,
+    /// Operation
+    pub op: IoOp,
+    /// Operation flags
+    pub flags: IoFlags,
+    /// Start sector
+    pub start_sector: u64,
+    /// Number of sectors, or number of zones to report for [`IoOp::ReportZones`]
+    pub nr_sectors: u32,
+    /// Data buffer, it holds the data to write or receives the data read.
+    /// It is empty for operations without payload, or if `data` is set.
+    pub buffer: &'a mut [u8],
+    /// Request data of read and write requests, only on direct copy queues,
+    /// see [`UblkQueue::direct_copy()`]
+    pub data: Option<RequestData<'a>>,
+    /// Registered buffer of read and write requests,
+    /// only on [`DeviceFlags::AutoBufReg`] devices
+    pub fixed_buf: Option<FixedBuffer<'a>>,
+    /// For [`IoOp::ZoneAppend`] only, it must be set to the first sector written
+    pub zone_append_lba: Option<&'a mut u64>,
+}
+
+impl IoRequest<'_> {
+    /// Fill the buffer of a [`IoOp::ReportZones`] request with `zones`,
+    /// returns the request result
+    ///
+    /// The remaining zone descriptors are zeroed, so the kernel driver stops at them.
+    #[must_use]
+    pub fn fill_zones(&mut self, zones: &[Zone]) -> i32 {
+        let mut zones = zones.iter();
+        let mut len = 0;
+
+        for desc in self.buffer.chunks_exact_mut(sys::BlkZone::SIZE) {
+            match zones.next() {
+                Some(zone) => sys::BlkZone::from(zone).write_to(desc),
+                None => desc.fill(0),
+            }
+            len += desc.len();
+        }
+
+        i32::try_from(len).unwrap_or(i32::MAX)
+    }
+}
+
+/// Request data, copied directly from/to the kernel driver
+///
+/// The data to write is read from it, and the data read is written into it,
+/// the copy is done with pread(2)/pwrite(2) on the device's char device.
+#[derive(Debug)]
+pub struct RequestData<'a> {
+    cdev: &'a File,
+    offset: u64,
+    len: usize,
+}
+
+impl RequestData<'_> {
+    /// Length of the request data in bytes
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the request has no data
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copy the request data starting at `offset` into `buf`,
+    /// returns the number of bytes copied
+    /// # Errors
+    ///
+    pub fn read_at(&self, buf: &mut [u8], offset: usize) -> io::Result<usize> {
+        let len = buf.len().min(self.len.saturating_sub(offset));
+        self.cdev
+            .read_exact_at(&mut buf[..len], self.offset + offset as u64)?;
+        Ok(len)
+    }
+
+    /// Copy `buf` into the request data starting at `offset`,
+    /// returns the number of bytes copied
+    /// # Errors
+    ///
+    pub fn write_at(&self, buf: &[u8], offset: usize) -> io::Result<usize> {
+        let len = buf.len().min(self.len.saturating_sub(offset));
+        self.cdev
+            .write_all_at(&buf[..len], self.offset + offset as u64)?;
+        Ok(len)
+    }
+
+    /// Char device offset of the request data, to copy it with other
+    /// means (e.g., io_uring) using [`as_fd()`](AsFd::as_fd)
+    #[must_use]
+    pub const fn file_offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl AsFd for RequestData<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.cdev.as_fd()
+    }
+}
+
+/// Allocator of the I/O buffers on [`DeviceFlags::NeedGetData`] devices
+///
+/// A buffer is requested when a request with data is received, and given back
+/// once the kernel driver is done with it, i.e., when the tag's next request
+/// is received.
+pub trait BufferPool: Send {
+    /// Returns a buffer at least `len` bytes long, a shorter one fails the request
+    fn alloc(&mut self, len: usize) -> Vec<u8>;
+
+    /// Gives back a buffer returned by [`alloc()`](Self::alloc)
+    fn free(&mut self, _buf: Vec<u8>) {}
+}
+
+impl<P: BufferPool + ?Sized> BufferPool for Box<P> {
+    fn alloc(&mut self, len: usize) -> Vec<u8> {
+        (**self).alloc(len)
+    }
+
+    fn free(&mut self, buf: Vec<u8>) {
+        (**self).free(buf);
+    }
+}
+
+// Default pool, the buffers are allocated on the heap and reused, there are
+// at most `depth` of them
+#[derive(Default)]
+struct HeapBufferPool {
+    free: Vec<Vec<u8>>,
+}
+
+impl BufferPool for HeapBufferPool {
+    fn alloc(&mut self, len: usize) -> Vec<u8> {
+        match self.free.pop() {
+            Some(mut buf) => {
+                buf.resize(len, 0);
+                buf
+            }
+            None => vec![0; len],
+        }
+    }
+
+    fn free(&mut self, buf: Vec<u8>) {
+        self.free.push(buf);
+    }
+}
+
+// Buffer taken from the pool, it's kept as raw parts so its address stays
+// valid while the kernel driver may access it
+struct PoolBuf {
+    ptr: *mut u8,
+    len: usize,
+    cap: usize,
+}
+
+// SAFETY: the buffer is exclusively owned, so it can be moved to another thread.
+unsafe impl Send for PoolBuf {}
+
+impl From<Vec<u8>> for PoolBuf {
+    fn from(buf: Vec<u8>) -> Self {
+        let mut buf = mem::ManuallyDrop::new(buf);
+        Self {
+            ptr: buf.as_mut_ptr(),
+            len: buf.len(),
+            cap: buf.capacity(),
+        }
+    }
+}
+
+impl From<PoolBuf> for Vec<u8> {
+    fn from(buf: PoolBuf) -> Self {
+        let buf = mem::ManuallyDrop::new(buf);
+        // SAFETY: the raw parts were taken from a Vec<u8> in `PoolBuf::from()`.
+        unsafe { Vec::from_raw_parts(buf.ptr, buf.len, buf.cap) }
+    }
+}
+
+impl Drop for PoolBuf {
+    fn drop(&mut self) {
+        // SAFETY: the raw parts were taken from a Vec<u8> in `PoolBuf::from()`.
+        drop(unsafe { Vec::from_raw_parts(self.ptr, self.len, self.cap) });
+    }
+}
+
+/// Request buffer registered as a fixed buffer of the queue's io_uring
+///
+/// The I/O is done with `READ_FIXED`/`WRITE_FIXED` operations submitted on the
+/// queue's ring, so the request data is never copied in userspace. The buffer
+/// is unregistered by the kernel driver when the request is committed.
+pub struct FixedBuffer<'a> {
+    ring: &'a mut IoUring,
+    deferred_cqes: &'a mut Vec<(u64, i32)>,
+    index: u16,
+    len: usize,
+}
+
+impl FixedBuffer<'_> {
+    /// Index of the buffer in the queue's io_uring fixed buffers table
+    #[must_use]
+    pub const fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Length of the request data in bytes
+    #[must_use]
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the request has no data
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Read the request data from `fd` at `offset` into the buffer,
+    /// returns the number of bytes read, short only at end of file
+    /// # Errors
+    ///
+    pub fn read_from(&mut self, fd: BorrowedFd<'_>, offset: u64) -> io::Result<usize> {
+        let index = self.index;
+        self.transfer(|pos, len| {
+            // The registered buffer is addressed by its offset
+            ReadFixed::new(Fd(fd.as_raw_fd()), pos as *mut u8, len, index)
+                .offset64((offset + pos as u64) as libc::off64_t)
+                .build()
+        })
+    }
+
+    /// Write the request data from the buffer to `fd` at `offset`,
+    /// returns the number of bytes written
+    /// # Errors
+    ///
+    pub fn write_to(&mut self, fd: BorrowedFd<'_>, offset: u64) -> io::Result<usize> {
+        let index = self.index;
+        self.transfer(|pos, len| {
+            WriteFixed::new(Fd(fd.as_raw_fd()), pos as *const u8, len, index)
+                .offset64((offset + pos as u64) as libc::off64_t)
+                .build()
+        })
+    }
+
+    // Submits the I/O built by `op` for the buffer range (position, length)
+    // until the whole buffer is transferred or the end of file is reached
+    fn transfer<F>(&mut self, op: F) -> io::Result<usize>
+    where
+        F: Fn(usize, u32) -> squeue::Entry,
+    {
+        let mut pos = 0;
+        while pos < self.len {
+            let len = u32::try_from(self.len - pos).unwrap_or(u32::MAX);
+            let sqe = op(pos, len).user_data(FIXED_BUF_USER_DATA);
+            match self.submit_and_wait(&sqe)? {
+                0 => break,
+                n => pos += n,
+            }
+        }
+        Ok(pos)
+    }
+
+    // Submits `sqe` and waits for its completion, the other completions
+    // are deferred to the queue. If waiting fails, its completion is
+    // received later on and ignored by the queue.
+    fn submit_and_wait(&mut self, sqe: &squeue::Entry) -> io::Result<usize> {
+        if self.ring.submission().is_full() {
+            self.ring.submit()?;
+        }
+        // SAFETY: the registered buffer lives until the request is committed,
+        // and we wait for the I/O completion.
+        unsafe { self.ring.submission().push(sqe) }
+            .map_err(|_| io::Error::from_raw_os_error(libc::EBUSY))?;
+
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                res => res?,
+            };
+
+            let mut res = None;
+            for cqe in self.ring.completion() {
+                if cqe.user_data() == FIXED_BUF_USER_DATA {
+                    res = Some(cqe.result());
+                } else {
+                    self.deferred_cqes.push((cqe.user_data(), cqe.result()));
+                }
+            }
+
+            match res {
+                Some(res) if res < 0 => return Err(io::Error::from_raw_os_error(-res)),
+                Some(res) => return Ok(res as usize),
+                None => continue,
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for FixedBuffer<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FixedBuffer")
+            .field("index", &self.index)
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+/// I/O operation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoOp {
+    /// Read data into the request buffer
+    Read,
+    /// Write the request buffer
+    Write,
+    /// Flush the volatile cache
+    Flush,
+    /// Discard sectors
+    Discard,
+    /// Write the same block to a range of sectors
+    WriteSame,
+    /// Write zeroes to a range of sectors
+    WriteZeroes,
+    /// Explicitly open the zone starting at `start_sector`
+    ZoneOpen,
+    /// Close the zone starting at `start_sector`
+    ZoneClose,
+    /// Transition the zone starting at `start_sector` to full
+    ZoneFinish,
+    /// Write the request buffer at the zone's write pointer
+    ZoneAppend,
+    /// Reset the write pointer of all the sequential zones
+    ZoneResetAll,
+    /// Reset the write pointer of the zone starting at `start_sector`
+    ZoneReset,
+    /// Report the zones starting from the one containing `start_sector`
+    ReportZones,
+    /// Operation not known by this library
+    Unknown(u8),
+}
+
+bitflags! {
+    /// I/O operation flags
+    #[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct IoFlags: u32 {
+        /// No driver retries of device errors
+        const FailFastDev = sys::IoDesc::F_FAILFAST_DEV;
+        /// No driver retries of transport errors
+        const FailFastTransport = sys::IoDesc::F_FAILFAST_TRANSPORT;
+        /// No driver retries of driver errors
+        const FailFastDriver = sys::IoDesc::F_FAILFAST_DRIVER;
+        /// Metadata I/O
+        const Meta = sys::IoDesc::F_META;
+        /// Forced unit access
+        const Fua = sys::IoDesc::F_FUA;
+        /// Do not free blocks when zeroing
+        const NoUnmap = sys::IoDesc::F_NOUNMAP;
+        /// Swap I/O
+        const Swap = sys::IoDesc::F_SWAP;
+    }
+}
+
+/// Zone descriptor, reported to the kernel driver on [`IoOp::ReportZones`] requests
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Zone {
+    /// Start sector
+    pub start: u64,
+    /// Length in sectors
+    pub len: u64,
+    /// Write pointer position
+    pub wp: u64,
+    /// Type
+    pub zone_type: ZoneType,
+    /// Condition
+    pub cond: ZoneCond,
+    /// Usable sectors, starting from `start`
+    pub capacity: u64,
+}
+
+/// Zone type
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ZoneType {
+    /// No write pointer, random writes are allowed
+    Conventional,
+    /// Writes must be sequential at the write pointer
+    SeqWriteRequired,
+    /// Sequential writes are preferred, random writes are allowed
+    SeqWritePreferred,
+}
+
+/// Zone condition
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ZoneCond {
+    /// Conventional zone, without write pointer
+    NotWp,
+    /// Nothing was written
+    Empty,
+    /// Opened by a write
+    ImplicitOpen,
+    /// Opened by a zone open operation
+    ExplicitOpen,
+    /// Closed, partially written
+    Closed,
+    /// Read-only
+    ReadOnly,
+    /// Full
+    Full,
+    /// Offline, neither readable nor writable
+    Offline,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum TagState {
+    // No command in flight
+    Idle,
+    // Waiting for the kernel driver to complete the command
+    InFlight,
+    // Waiting for the kernel driver to copy the data of a write request
+    GettingData,
+    // Aborted, the tag won't be fetched again
+    Done,
+}
+
+// Ring of the I/O commands, sent to the kernel driver or handled by a mock
+enum CmdRing {
+    Uring(Box<IoUring>),
+    #[cfg(any(test, feature = "mock"))]
+    Mock(mock::MockRing),
+}
+
+impl CmdRing {
+    fn as_uring(&mut self) -> Option<&mut IoUring> {
+        match self {
+            Self::Uring(ring) => Some(ring),
+            #[cfg(any(test, feature = "mock"))]
+            Self::Mock(_) => None,
+        }
+    }
+
+    fn submit(&mut self) -> io::Result<()> {
+        match self {
+            Self::Uring(ring) => ring.submit().map(|_| ()),
+            #[cfg(any(test, feature = "mock"))]
+            Self::Mock(ring) => {
+                ring.submit();
+                Ok(())
+            }
+        }
+    }
+
+    // Submits the queued commands and waits for at least `want` completions,
+    // they are appended to `cqes` as user data and result
+    fn submit_and_wait(&mut self, want: usize, cqes: &mut Vec<(u64, i32)>) -> io::Result<()> {
+        match self {
+            Self::Uring(ring) => {
+                loop {
+                    match ring.submit_and_wait(want) {
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                        res => res?,
+                    };
+                    break;
+                }
+                cqes.extend(ring.completion().map(|cqe| (cqe.user_data(), cqe.result())));
+            }
+            #[cfg(any(test, feature = "mock"))]
+            Self::Mock(ring) => ring.submit_and_wait(want, cqes),
+        }
+        Ok(())
+    }
+}
+
+// The tags are 16bit, it can't match any fetch command
+const FIXED_BUF_USER_DATA: u64 = u64::MAX;
+
+#[inline]
+const fn tag_to_user_data(tag: u16) -> u64 {
+    tag as u64
+}
+
+#[inline]
+const fn user_data_to_tag(user_data: u64) -> u16 {
+    user_data as u16
+}
+
+// I/O buffers of the tags, either one mapped region for all of them,
+// or the buffers taken from the pool
+struct IoBufs {
+    region: Option<MmapRegion>,
+    pool_bufs: Vec<Option<PoolBuf>>,
+    // size of each tag's buffer in `region`
+    buf_size: usize,
+}
+
+impl IoBufs {
+    fn addr(&self, tag: u16) -> *mut u8 {
+        match &self.region {
+            // SAFETY: the offset is within the mapping, it's `depth * buf_size` bytes long.
+            Some(region) => unsafe {
+                region
+                    .addr
+                    .cast::<u8>()
+                    .add(usize::from(tag) * self.buf_size)
+            },
+            None => self.pool_bufs[usize::from(tag)]
+                .as_ref()
+                .map_or(ptr::null_mut(), |buf| buf.ptr),
+        }
+    }
+
+    // The first `len` bytes of the tag's buffer, the buffers can't be
+    // released nor borrowed again while the slice is alive
+    fn get_mut(&mut self, tag: u16, len: usize) -> &mut [u8] {
+        if len == 0 {
+            return &mut [];
+        }
+
+        assert!(len <= self.buf_size);
+        if self.region.is_none() {
+            assert!(self.pool_bufs[usize::from(tag)]
+                .as_ref()
+                .is_some_and(|buf| len <= buf.len));
+        }
+        // SAFETY: the buffer is at least `len` bytes long and `self` is mutably
+        // borrowed for the slice lifetime. The kernel driver doesn't touch the
+        // buffer until the request is committed.
+        unsafe { slice::from_raw_parts_mut(self.addr(tag), len) }
+    }
+}
+
+// Memory mapped region, it is unmapped on drop
+struct MmapRegion {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the region is exclusively owned, so it can be moved to another thread.
+unsafe impl Send for MmapRegion {}
+
+impl MmapRegion {
+    fn new(len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> io::Result<Self> {
+        // SAFETY: we let the kernel choose the address, so no existing mapping is replaced.
+        let addr = unsafe { libc::mmap(ptr::null_mut(), len, prot, flags, fd, offset) };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self { addr, len })
+    }
+}
+
+impl Drop for MmapRegion {
+    fn drop(&mut self) {
+        // SAFETY: `addr` and `len` describe a mapping created in `MmapRegion::new()`.
+        unsafe {
+            libc::munmap(self.addr, self.len);
+        }
+    }
+}
+
+pub(crate) fn page_size() -> usize {
+    // SAFETY: sysconf() has no memory safety requirements.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
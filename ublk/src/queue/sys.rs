@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: MIT
+
+use crate::control::CmdEncoding;
+use crate::queue::{page_size, IoFlags, IoOp, Zone, ZoneCond, ZoneType};
+use io_uring::squeue;
+use std::mem;
+
+// IO command numbers handled by ublk kernel driver (issued to /dev/ublkcN).
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoCmdOp {
+    FetchReq = 0x20,
+    CommitAndFetchReq = 0x21,
+    // Sets the buffer address of a write request, the kernel driver copies
+    // the data into it and completes the command with IO_RES_OK.
+    NeedGetData = 0x22,
+}
+
+impl IoCmdOp {
+    // Returns the opcode to be sent to the kernel driver
+    pub const fn opcode(self, encoding: CmdEncoding) -> u32 {
+        encoding.iowr(self as u32, mem::size_of::<IoCmd>())
+    }
+}
+
+// IO command results, only ABORT means that no re-fetch
+pub const IO_RES_OK: i32 = 0;
+// With NEED_GET_DATA, the write request needs a buffer before its data is copied
+pub const IO_RES_NEED_GET_DATA: i32 = 1;
+pub const IO_RES_ABORT: i32 = -libc::ENODEV;
+
+// Offset of the per-queue io descriptors area in /dev/ublkcN
+pub const CMD_BUF_OFFSET: i64 = 0;
+
+// The per-queue io descriptors area is always reserved for the
+// maximum queue depth supported by the kernel driver, the tag is 16bit,
+// but so far the driver limits it to at most 4096 IOs for each queue.
+pub const MAX_QUEUE_DEPTH: usize = 4096;
+
+// Offset of the io descriptors area of the queue `q_id` in /dev/ublkcN,
+// each queue's area is rounded up to the page size
+#[inline]
+pub fn descs_offset(q_id: u16) -> i64 {
+    let area_size = (MAX_QUEUE_DEPTH * mem::size_of::<IoDesc>()).next_multiple_of(page_size());
+    CMD_BUF_OFFSET + i64::from(q_id) * area_size as i64
+}
+
+pub const SECTOR_SHIFT: u32 = 9;
+
+// With user copy, the request data is read/written with pread(2)/pwrite(2) on
+// /dev/ublkcN, at an offset that encodes the request queue id and tag:
+// IO_BUF_OFFSET + (q_id << (IO_BUF_BITS + TAG_BITS)) | (tag << IO_BUF_BITS)
+const IO_BUF_OFFSET: u64 = 0x8000_0000;
+const IO_BUF_BITS: u32 = 25;
+const TAG_BITS: u32 = 16;
+
+#[inline]
+pub const fn user_copy_offset(q_id: u16, tag: u16) -> u64 {
+    IO_BUF_OFFSET + (((q_id as u64) << (IO_BUF_BITS + TAG_BITS)) | ((tag as u64) << IO_BUF_BITS))
+}
+
+// With auto buffer registration, struct ublk_auto_buf_reg is passed in
+// sqe->addr of the fetch/commit commands:
+// index: u16 (fixed buffer index), flags: u8, reserved0: u8, reserved1: u32
+#[inline]
+pub const fn auto_buf_reg_addr(index: u16) -> u64 {
+    index as u64
+}
+
+// Offset of the addr field in struct io_uring_sqe
+const SQE_ADDR_OFFSET: usize = 16;
+
+// UringCmd16 doesn't let to set sqe->addr, patch the built entry
+pub fn set_sqe_addr(sqe: &mut squeue::Entry, addr: u64) {
+    const _: () = assert!(mem::size_of::<squeue::Entry>() == 64, "invalid size");
+    // SAFETY: squeue::Entry is a repr(C) wrapper of struct io_uring_sqe,
+    // the 64bit addr field is at SQE_ADDR_OFFSET.
+    unsafe {
+        (sqe as *mut squeue::Entry)
+            .cast::<u8>()
+            .add(SQE_ADDR_OFFSET)
+            .cast::<u64>()
+            .write_unaligned(addr);
+    }
+}
+
+// IO command data (to be sent into UringCmd16::cmd)
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct IoCmd {
+    q_id: u16,
+    // for fetch/commit which result
+    tag: u16,
+    // io result, it is valid for COMMIT* command only
+    result: i32,
+    // userspace buffer address in ublksrv daemon process, valid for
+    // FETCH* command only. For the COMMIT of a zone append request
+    // it's the first sector written (zone_append_lba).
+    addr: u64,
+}
+
+const _: () = assert!(mem::size_of::<IoCmd>() == 16, "invalid size");
+
+impl IoCmd {
+    #[inline]
+    pub const fn new(q_id: u16, tag: u16) -> Self {
+        Self {
+            q_id,
+            tag,
+            result: 0,
+            addr: 0,
+        }
+    }
+
+    #[inline]
+    pub const fn result(mut self, result: i32) -> Self {
+        self.result = result;
+        self
+    }
+
+    #[inline]
+    pub const fn addr(mut self, addr: u64) -> Self {
+        self.addr = addr;
+        self
+    }
+
+    #[inline]
+    pub const fn zone_append_lba(mut self, lba: u64) -> Self {
+        self.addr = lba;
+        self
+    }
+
+    #[inline]
+    pub const fn q_id(&self) -> u16 {
+        self.q_id
+    }
+
+    #[inline]
+    pub const fn tag(&self) -> u16 {
+        self.tag
+    }
+
+    #[inline]
+    pub const fn io_result(&self) -> i32 {
+        self.result
+    }
+
+    #[inline]
+    pub const fn io_addr(&self) -> u64 {
+        self.addr
+    }
+}
+
+impl From<IoCmd> for [u8; 16] {
+    fn from(cmd: IoCmd) -> Self {
+        let mut data = [0_u8; 16];
+        // SAFETY: `data` is valid for writes and `IoCmd` is exactly 16 bytes.
+        unsafe {
+            data.as_mut_ptr().cast::<IoCmd>().write_unaligned(cmd);
+        }
+        data
+    }
+}
+
+// IO descriptor, it is stored by the ublk driver in the shared memory
+// and read by the server after a fetch command completes, indexed by
+// request tag.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct IoDesc {
+    // op: bit 0-7, flags: bit 8-31
+    op_flags: u32,
+    // number of zones for REPORT_ZONES
+    nr_sectors: u32,
+    // start sector for this io
+    start_sector: u64,
+    // buffer address in ublksrv daemon vm space, from ublk driver
+    addr: u64,
+}
+
+impl IoDesc {
+    // Available IO operations
+    pub const OP_READ: u8 = 0;
+    pub const OP_WRITE: u8 = 1;
+    pub const OP_FLUSH: u8 = 2;
+    pub const OP_DISCARD: u8 = 3;
+    pub const OP_WRITE_SAME: u8 = 4;
+    pub const OP_WRITE_ZEROES: u8 = 5;
+    pub const OP_ZONE_OPEN: u8 = 10;
+    pub const OP_ZONE_CLOSE: u8 = 11;
+    pub const OP_ZONE_FINISH: u8 = 12;
+    pub const OP_ZONE_APPEND: u8 = 13;
+    pub const OP_ZONE_RESET_ALL: u8 = 14;
+    pub const OP_ZONE_RESET: u8 = 15;
+    pub const OP_REPORT_ZONES: u8 = 18;
+
+    // Available IO flags
+    pub const F_FAILFAST_DEV: u32 = 1 << 8;
+    pub const F_FAILFAST_TRANSPORT: u32 = 1 << 9;
+    pub const F_FAILFAST_DRIVER: u32 = 1 << 10;
+    pub const F_META: u32 = 1 << 11;
+    pub const F_FUA: u32 = 1 << 13;
+    pub const F_NOUNMAP: u32 = 1 << 15;
+    pub const F_SWAP: u32 = 1 << 16;
+
+    // Descriptor written by the mock char device
+    #[cfg(any(test, feature = "mock"))]
+    #[inline]
+    pub const fn new(op: IoOp, flags: IoFlags, start_sector: u64, nr_sectors: u32) -> Self {
+        Self {
+            op_flags: op.code() as u32 | flags.bits(),
+            nr_sectors,
+            start_sector,
+            addr: 0,
+        }
+    }
+
+    #[inline]
+    pub const fn op(&self) -> IoOp {
+        match (self.op_flags & 0xff) as u8 {
+            Self::OP_READ => IoOp::Read,
+            Self::OP_WRITE => IoOp::Write,
+            Self::OP_FLUSH => IoOp::Flush,
+            Self::OP_DISCARD => IoOp::Discard,
+            Self::OP_WRITE_SAME => IoOp::WriteSame,
+            Self::OP_WRITE_ZEROES => IoOp::WriteZeroes,
+            Self::OP_ZONE_OPEN => IoOp::ZoneOpen,
+            Self::OP_ZONE_CLOSE => IoOp::ZoneClose,
+            Self::OP_ZONE_FINISH => IoOp::ZoneFinish,
+            Self::OP_ZONE_APPEND => IoOp::ZoneAppend,
+            Self::OP_ZONE_RESET_ALL => IoOp::ZoneResetAll,
+            Self::OP_ZONE_RESET => IoOp::ZoneReset,
+            Self::OP_REPORT_ZONES => IoOp::ReportZones,
+            op => IoOp::Unknown(op),
+        }
+    }
+
+    #[inline]
+    pub const fn flags(&self) -> IoFlags {
+        IoFlags::from_bits_truncate(self.op_flags & !0xff)
+    }
+
+    #[inline]
+    pub const fn nr_sectors(&self) -> u32 {
+        self.nr_sectors
+    }
+
+    #[inline]
+    pub const fn nr_zones(&self) -> u32 {
+        self.nr_sectors
+    }
+
+    #[inline]
+    pub const fn start_sector(&self) -> u64 {
+        self.start_sector
+    }
+}
+
+impl IoOp {
+    // Returns the operation code of the I/O descriptors
+    pub const fn code(self) -> u8 {
+        match self {
+            Self::Read => IoDesc::OP_READ,
+            Self::Write => IoDesc::OP_WRITE,
+            Self::Flush => IoDesc::OP_FLUSH,
+            Self::Discard => IoDesc::OP_DISCARD,
+            Self::WriteSame => IoDesc::OP_WRITE_SAME,
+            Self::WriteZeroes => IoDesc::OP_WRITE_ZEROES,
+            Self::ZoneOpen => IoDesc::OP_ZONE_OPEN,
+            Self::ZoneClose => IoDesc::OP_ZONE_CLOSE,
+            Self::ZoneFinish => IoDesc::OP_ZONE_FINISH,
+            Self::ZoneAppend => IoDesc::OP_ZONE_APPEND,
+            Self::ZoneResetAll => IoDesc::OP_ZONE_RESET_ALL,
+            Self::ZoneReset => IoDesc::OP_ZONE_RESET,
+            Self::ReportZones => IoDesc::OP_REPORT_ZONES,
+            Self::Unknown(op) => op,
+        }
+    }
+}
+
+// Zone descriptor (struct blk_zone), REPORT_ZONES requests are filled
+// with an array of them.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct BlkZone {
+    start: u64,  // zone start sector
+    len: u64,    // zone length in number of sectors
+    wp: u64,     // zone write pointer position
+    type_: u8,   // zone type
+    cond: u8,    // zone condition
+    non_seq: u8, // non-sequential write resources active
+    reset: u8,   // reset write pointer recommended
+    _resv: [u8; 4],
+    capacity: u64, // zone capacity in number of sectors
+    _reserved: [u8; 24],
+}
+
+const _: () = assert!(mem::size_of::<BlkZone>() == 64, "invalid size");
+
+impl BlkZone {
+    pub const SIZE: usize = mem::size_of::<Self>();
+
+    // Zone types
+    pub const TYPE_CONVENTIONAL: u8 = 1;
+    pub const TYPE_SEQWRITE_REQ: u8 = 2;
+    pub const TYPE_SEQWRITE_PREF: u8 = 3;
+
+    // Zone conditions
+    pub const COND_NOT_WP: u8 = 0;
+    pub const COND_EMPTY: u8 = 1;
+    pub const COND_IMP_OPEN: u8 = 2;
+    pub const COND_EXP_OPEN: u8 = 3;
+    pub const COND_CLOSED: u8 = 4;
+    pub const COND_READONLY: u8 = 0xd;
+    pub const COND_FULL: u8 = 0xe;
+    pub const COND_OFFLINE: u8 = 0xf;
+
+    // Copies the zone descriptor into `buf`, that must be at least `SIZE` bytes long
+    pub fn write_to(&self, buf: &mut [u8]) {
+        assert!(buf.len() >= Self::SIZE);
+        // SAFETY: `buf` is valid for writes of `SIZE` bytes.
+        unsafe {
+            buf.as_mut_ptr().cast::<Self>().write_unaligned(*self);
+        }
+    }
+}
+
+impl From<&Zone> for BlkZone {
+    fn from(zone: &Zone) -> Self {
+        Self {
+            start: zone.start,
+            len: zone.len,
+            wp: zone.wp,
+            type_: match zone.zone_type {
+                ZoneType::Conventional => Self::TYPE_CONVENTIONAL,
+                ZoneType::SeqWriteRequired => Self::TYPE_SEQWRITE_REQ,
+                ZoneType::SeqWritePreferred => Self::TYPE_SEQWRITE_PREF,
+            },
+            cond: match zone.cond {
+                ZoneCond::NotWp => Self::COND_NOT_WP,
+                ZoneCond::Empty => Self::COND_EMPTY,
+                ZoneCond::ImplicitOpen => Self::COND_IMP_OPEN,
+                ZoneCond::ExplicitOpen => Self::COND_EXP_OPEN,
+                ZoneCond::Closed => Self::COND_CLOSED,
+                ZoneCond::ReadOnly => Self::COND_READONLY,
+                ZoneCond::Full => Self::COND_FULL,
+                ZoneCond::Offline => Self::COND_OFFLINE,
+            },
+            capacity: zone.capacity,
+            ..Self::default()
+        }
+    }
+}
@@ -0,0 +1,704 @@
+// SPDX-License-Identifier: MIT
+
+use crate::control::DeviceInfo;
+use crate::error::{Error, Result};
+#[cfg(any(test, feature = "mock"))]
+use crate::queue::MockCharDevice;
+use crate::queue::{
+    BufferPool, FixedBuffer, IoFlags, IoOp, IoRequest, RequestData, UblkQueue, Zone,
+};
+use std::io;
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+
+/// Block device backend
+///
+/// A target provides the storage of a ublk device. Every hardware queue is served
+/// by its own thread, so a target is shared among them. Sectors are
+/// [`UblkQueue::SECTOR_SIZE`] bytes long.
+///
+/// Only `read` and `write` are mandatory, by default `flush` and `resize` do
+/// nothing and the other operations fail with `EOPNOTSUPP`.
+pub trait BlockTarget: Send + Sync {
+    /// Read `buf.len()` bytes starting at `sector`, returns the number of bytes read
+    /// # Errors
+    ///
+    fn read(&self, sector: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Write `buf` starting at `sector`, returns the number of bytes written
+    /// # Errors
+    ///
+    fn write(&self, sector: u64, buf: &[u8]) -> io::Result<usize>;
+
+    /// Returns `true` if the target copies the request data itself on
+    /// [`DeviceFlags::UserCopy`](crate::control::DeviceFlags::UserCopy) devices,
+    /// with [`read_direct()`](Self::read_direct) and [`write_direct()`](Self::write_direct)
+    fn direct_copy(&self) -> bool {
+        false
+    }
+
+    /// Read `data.len()` bytes starting at `sector` directly into the request data,
+    /// returns the number of bytes read
+    /// # Errors
+    ///
+    fn read_direct(&self, _sector: u64, _data: &RequestData<'_>) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Write the request data starting at `sector`, returns the number of bytes written
+    /// # Errors
+    ///
+    fn write_direct(&self, _sector: u64, _data: &RequestData<'_>) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Read `buf.len()` bytes starting at `sector` into the registered request buffer,
+    /// returns the number of bytes read
+    ///
+    /// It's called instead of [`read()`](Self::read) on
+    /// [`DeviceFlags::AutoBufReg`](crate::control::DeviceFlags::AutoBufReg) devices.
+    /// # Errors
+    ///
+    fn read_fixed(&self, _sector: u64, _buf: &mut FixedBuffer<'_>) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Write the registered request buffer starting at `sector`,
+    /// returns the number of bytes written
+    ///
+    /// It's called instead of [`write()`](Self::write) on
+    /// [`DeviceFlags::AutoBufReg`](crate::control::DeviceFlags::AutoBufReg) devices.
+    /// # Errors
+    ///
+    fn write_fixed(&self, _sector: u64, _buf: &mut FixedBuffer<'_>) -> io::Result<usize> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Flush the volatile cache to the backing storage
+    /// # Errors
+    ///
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Discard `nr_sectors` starting at `sector`
+    /// # Errors
+    ///
+    fn discard(&self, _sector: u64, _nr_sectors: u32) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Write zeroes to `nr_sectors` starting at `sector`
+    /// # Errors
+    ///
+    fn write_zeroes(&self, _sector: u64, _nr_sectors: u32) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Resize the backing storage to `sectors`
+    ///
+    /// It's called by [`UblkDevice::resize()`](crate::device::UblkDevice::resize)
+    /// before the kernel driver changes the device capacity, by default the
+    /// storage is left untouched, as when the capacity is changed by `ublkctl resize`.
+    /// Targets with a fixed size backing storage should fail on growth.
+    /// # Errors
+    ///
+    fn resize(&self, _sectors: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Report up to `nr_zones` zones, starting from the one containing `sector`
+    /// # Errors
+    ///
+    fn report_zones(&self, _sector: u64, _nr_zones: u32) -> io::Result<Vec<Zone>> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Apply `action` to the zone starting at `sector`
+    /// (`sector` is meaningless for [`ZoneAction::ResetAll`])
+    /// # Errors
+    ///
+    fn manage_zone(&self, _action: ZoneAction, _sector: u64) -> io::Result<()> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Write `buf` at the write pointer of the zone starting at `sector`,
+    /// returns the first sector written
+    /// # Errors
+    ///
+    fn zone_append(&self, _sector: u64, _buf: &[u8]) -> io::Result<u64> {
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+}
+
+/// Zone management action
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ZoneAction {
+    /// Explicitly open the zone
+    Open,
+    /// Close the zone
+    Close,
+    /// Transition the zone to full
+    Finish,
+    /// Reset the zone's write pointer
+    Reset,
+    /// Reset the write pointer of all the sequential zones
+    ResetAll,
+}
+
+/// Handle an I/O request with `target`
+///
+/// Returns the request result to be committed to the kernel driver: the number of
+/// bytes transferred or a negative errno.
+pub fn handle_io<T: BlockTarget + ?Sized>(target: &T, mut req: IoRequest<'_>) -> i32 {
+    let res = match req.op {
+        IoOp::Read => match (&req.data, &mut req.fixed_buf) {
+            (Some(data), _) => target.read_direct(req.start_sector, data),
+            (None, Some(buf)) => target.read_fixed(req.start_sector, buf),
+            (None, None) => target.read(req.start_sector, req.buffer),
+        },
+        IoOp::Write => match (&req.data, &mut req.fixed_buf) {
+            (Some(data), _) => target.write_direct(req.start_sector, data),
+            (None, Some(buf)) => target.write_fixed(req.start_sector, buf),
+            (None, None) => target.write(req.start_sector, req.buffer),
+        }
+        .and_then(|len| {
+            if req.flags.contains(IoFlags::Fua) {
+                target.flush()?;
+            }
+            Ok(len)
+        }),
+        IoOp::Flush => target.flush().map(|_| 0),
+        IoOp::Discard => target.discard(req.start_sector, req.nr_sectors).map(|_| 0),
+        IoOp::WriteZeroes => target
+            .write_zeroes(req.start_sector, req.nr_sectors)
+            .map(|_| 0),
+        IoOp::ZoneOpen => zone_action(target, ZoneAction::Open, req.start_sector),
+        IoOp::ZoneClose => zone_action(target, ZoneAction::Close, req.start_sector),
+        IoOp::ZoneFinish => zone_action(target, ZoneAction::Finish, req.start_sector),
+        IoOp::ZoneReset => zone_action(target, ZoneAction::Reset, req.start_sector),
+        IoOp::ZoneResetAll => zone_action(target, ZoneAction::ResetAll, req.start_sector),
+        IoOp::ZoneAppend => target.zone_append(req.start_sector, req.buffer).map(|lba| {
+            if let Some(zone_append_lba) = req.zone_append_lba.take() {
+                *zone_append_lba = lba;
+            }
+            req.buffer.len()
+        }),
+        IoOp::ReportZones => target
+            .report_zones(req.start_sector, req.nr_sectors)
+            .map(|zones| req.fill_zones(&zones) as usize),
+        IoOp::WriteSame | IoOp::Unknown(_) => Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP)),
+    };
+
+    match res {
+        Ok(len) => i32::try_from(len).unwrap_or(i32::MAX),
+        Err(err) => -err.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
+
+fn zone_action<T: BlockTarget + ?Sized>(
+    target: &T,
+    action: ZoneAction,
+    sector: u64,
+) -> io::Result<usize> {
+    target.manage_zone(action, sector).map(|_| 0)
+}
+
+/// Serve the queue's I/O requests with `target` until the kernel driver aborts the queue
+///
+/// The fetch commands must be already submitted,
+/// see [`UblkQueue::submit_fetch_commands()`].
+/// # Errors
+///
+pub fn serve_queue<T: BlockTarget + ?Sized>(queue: &mut UblkQueue, target: &T) -> Result<()> {
+    queue.run(|req| handle_io(target, req))
+}
+
+/// Target driver
+///
+/// It wires a [`BlockTarget`] to every hardware queue of a device, each queue
+/// is served by its own thread.
+pub struct TargetDriver<T> {
+    info: DeviceInfo,
+    target: Arc<T>,
+    affinity: Vec<libc::cpu_set_t>,
+    new_pool: Option<NewBufferPool>,
+    #[cfg(any(test, feature = "mock"))]
+    mock: Option<MockCharDevice>,
+}
+
+// Creates the buffer pool of the queue `q_id`
+pub(crate) type NewBufferPool = Arc<dyn Fn(u16) -> Box<dyn BufferPool> + Send + Sync>;
+
+impl<T: BlockTarget + 'static> TargetDriver<T> {
+    /// Target driver constructor, `info` is the device information returned
+    /// by [`UblkCtrl::add_device()`](crate::control::UblkCtrl::add_device)
+    #[must_use]
+    pub fn new(info: DeviceInfo, target: Arc<T>) -> Self {
+        Self {
+            info,
+            target,
+            affinity: Vec::new(),
+            new_pool: None,
+            #[cfg(any(test, feature = "mock"))]
+            mock: None,
+        }
+    }
+
+    /// Sets the queues cpu affinity, the thread serving the queue `q_id` is
+    /// pinned to the cpus in `affinity[q_id]`, see
+    /// [`UblkCtrl::get_all_queues_affinity()`](crate::control::UblkCtrl::get_all_queues_affinity)
+    #[must_use]
+    pub fn affinity(mut self, affinity: Vec<libc::cpu_set_t>) -> Self {
+        self.affinity = affinity;
+        self
+    }
+
+    /// Sets the pool of the I/O buffers of each queue, created by `new_pool(q_id)`,
+    /// see [`UblkQueue::buffer_pool()`]
+    #[must_use]
+    pub fn buffer_pool<P, F>(mut self, new_pool: F) -> Self
+    where
+        P: BufferPool + 'static,
+        F: Fn(u16) -> P + Send + Sync + 'static,
+    {
+        self.new_pool = Some(Arc::new(move |q_id| Box::new(new_pool(q_id))));
+        self
+    }
+
+    /// Serves the queues of the emulated char device `device` instead of the
+    /// kernel driver's, see [`UblkQueue::with_mock()`]
+    #[cfg(any(test, feature = "mock"))]
+    #[must_use]
+    pub fn mock(mut self, device: MockCharDevice) -> Self {
+        self.mock = Some(device);
+        self
+    }
+
+    // Sets the already type-erased pool constructor
+    pub(crate) fn new_buffer_pool(mut self, new_pool: Option<NewBufferPool>) -> Self {
+        self.new_pool = new_pool;
+        self
+    }
+
+    /// Spawn one thread per hardware queue
+    ///
+    /// It returns once every queue has submitted its fetch commands, so the device
+    /// is ready to be started.
+    /// # Errors
+    ///
+    /// If a queue fails to set up, the queues already set up are closed and all
+    /// the threads are joined before returning the error. A queue thread that
+    /// panicked while setting up its queue fails with [`Error::QueuePanicked`].
+    pub fn spawn(&self) -> Result<QueueThreads> {
+        let (tx, rx) = mpsc::channel();
+        let mut handles = Vec::with_capacity(usize::from(self.info.nr_hw_queues));
+        // tells every thread whether to serve its queue or close it
+        let mut serve_txs = Vec::with_capacity(usize::from(self.info.nr_hw_queues));
+        let mut res = Ok(());
+
+        for q_id in 0..self.info.nr_hw_queues {
+            let info = self.info;
+            let target = Arc::clone(&self.target);
+            let tx = tx.clone();
+            let (serve_tx, serve_rx) = mpsc::channel();
+            let cpu_set = self.affinity.get(usize::from(q_id)).copied();
+            let new_pool = self.new_pool.clone();
+            #[cfg(any(test, feature = "mock"))]
+            let mock = self.mock.clone();
+
+            let spawned = thread::Builder::new()
+                .name(format!("ublk{}q{}", info.dev_id, q_id))
+                .spawn(move || {
+                    let mut queue = match set_thread_affinity(cpu_set.as_ref())
+                        .and_then(|()| {
+                            // The attached queue doesn't keep the device alive
+                            #[cfg(any(test, feature = "mock"))]
+                            if let Some(mock) = mock {
+                                return UblkQueue::with_mock(&mock, q_id);
+                            }
+                            UblkQueue::new(&info, q_id)
+                        })
+                        .map(|q| q.direct_copy(target.direct_copy()))
+                        .map(|q| match &new_pool {
+                            Some(new_pool) => q.buffer_pool(new_pool(q_id)),
+                            None => q,
+                        })
+                        .and_then(|mut q| q.submit_fetch_commands().map(|_| q))
+                    {
+                        Ok(queue) => queue,
+                        Err(err) => {
+                            let _ = tx.send(Err(err));
+                            return Ok(());
+                        }
+                    };
+
+                    let _ = tx.send(Ok(()));
+                    // The channel disconnects once every thread is set up or gone
+                    drop(tx);
+                    // The queue's ring is closed on drop, canceling its fetch commands
+                    if serve_rx.recv() != Ok(true) {
+                        return Ok(());
+                    }
+                    serve_queue(&mut queue, target.as_ref())
+                });
+            match spawned {
+                Ok(handle) => {
+                    handles.push(handle);
+                    serve_txs.push(serve_tx);
+                }
+                Err(err) => {
+                    res = Err(err.into());
+                    break;
+                }
+            }
+        }
+        drop(tx);
+
+        // Disconnected early if a thread panicked before sending its result
+        let mut all_ready = true;
+        for _ in 0..handles.len() {
+            match rx.recv() {
+                Ok(ready) => {
+                    if res.is_ok() {
+                        res = ready;
+                    }
+                }
+                Err(_) => {
+                    all_ready = false;
+                    break;
+                }
+            }
+        }
+
+        let serve = all_ready && res.is_ok();
+        for serve_tx in serve_txs {
+            let _ = serve_tx.send(serve);
+        }
+
+        if serve {
+            return Ok(QueueThreads { handles });
+        }
+
+        for (q_id, handle) in (0..).zip(handles) {
+            if handle.join().is_err() && res.is_ok() {
+                res = Err(Error::QueuePanicked {
+                    dev_id: self.info.dev_id,
+                    q_id,
+                });
+            }
+        }
+        res?;
+        // A thread went away without reporting, though none panicked
+        Err(io::Error::from_raw_os_error(libc::EIO).into())
+    }
+}
+
+/// Handles of the threads serving the device's queues
+pub struct QueueThreads {
+    handles: Vec<JoinHandle<Result<()>>>,
+}
+
+impl QueueThreads {
+    /// Wait for all the queue threads to finish, i.e., until the device is stopped
+    /// # Errors
+    ///
+    /// Returns the first error reported by a queue thread.
+    /// # Panics
+    ///
+    /// Panics if a queue thread panicked.
+    pub fn join(self) -> Result<()> {
+        let mut res = Ok(());
+        for handle in self.handles {
+            let thread_res = handle.join().expect("queue thread panicked");
+            if res.is_ok() {
+                res = thread_res;
+            }
+        }
+        res
+    }
+}
+
+fn set_thread_affinity(cpu_set: Option<&libc::cpu_set_t>) -> Result<()> {
+    let Some(cpu_set) = cpu_set else {
+        return Ok(());
+    };
+
+    // SAFETY: `cpu_set` is a valid cpu set, and pid 0 means the calling thread.
+    let ret =
+        unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), cpu_set) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{DeviceFlags, DeviceState};
+    use crate::queue::{MockCharDevice, MockCompletion, MockIo, ZoneCond, ZoneType};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    const DISK_SIZE: usize = 1 << 20;
+    const ZONE_SECTORS: u64 = 256;
+
+    // In-memory disk made of sequential zones, counting the flushes
+    struct RamTarget {
+        disk: Mutex<Vec<u8>>,
+        wps: Mutex<Vec<u64>>,
+        flushes: AtomicUsize,
+    }
+
+    impl RamTarget {
+        fn new() -> Arc<Self> {
+            let nr_zones = (DISK_SIZE as u64 >> 9) / ZONE_SECTORS;
+            Arc::new(Self {
+                disk: Mutex::new(vec![0; DISK_SIZE]),
+                wps: Mutex::new((0..nr_zones).map(|zone| zone * ZONE_SECTORS).collect()),
+                flushes: AtomicUsize::new(0),
+            })
+        }
+
+        fn range(sector: u64, len: usize) -> io::Result<std::ops::Range<usize>> {
+            let offset = (sector as usize) << 9;
+            if offset + len > DISK_SIZE {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            Ok(offset..offset + len)
+        }
+    }
+
+    impl BlockTarget for RamTarget {
+        fn read(&self, sector: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let range = Self::range(sector, buf.len())?;
+            buf.copy_from_slice(&self.disk.lock().unwrap()[range]);
+            Ok(buf.len())
+        }
+
+        fn write(&self, sector: u64, buf: &[u8]) -> io::Result<usize> {
+            let range = Self::range(sector, buf.len())?;
+            self.disk.lock().unwrap()[range].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            self.flushes.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn discard(&self, sector: u64, nr_sectors: u32) -> io::Result<()> {
+            let range = Self::range(sector, (nr_sectors as usize) << 9)?;
+            self.disk.lock().unwrap()[range].fill(0);
+            Ok(())
+        }
+
+        fn report_zones(&self, sector: u64, nr_zones: u32) -> io::Result<Vec<Zone>> {
+            let wps = self.wps.lock().unwrap();
+            let first = (sector / ZONE_SECTORS) as usize;
+            Ok(wps
+                .iter()
+                .enumerate()
+                .skip(first)
+                .take(nr_zones as usize)
+                .map(|(zone, &wp)| Zone {
+                    start: zone as u64 * ZONE_SECTORS,
+                    len: ZONE_SECTORS,
+                    wp,
+                    zone_type: ZoneType::SeqWriteRequired,
+                    cond: ZoneCond::ImplicitOpen,
+                    capacity: ZONE_SECTORS,
+                })
+                .collect())
+        }
+
+        fn zone_append(&self, sector: u64, buf: &[u8]) -> io::Result<u64> {
+            let mut wps = self.wps.lock().unwrap();
+            let wp = &mut wps[(sector / ZONE_SECTORS) as usize];
+            let lba = *wp;
+            self.write(lba, buf)?;
+            *wp += buf.len() as u64 >> 9;
+            Ok(lba)
+        }
+    }
+
+    fn info(flags: DeviceFlags, nr_hw_queues: u16) -> DeviceInfo {
+        DeviceInfo {
+            dev_id: 0,
+            srv_pid: -1,
+            active: false,
+            state: DeviceState::Dead,
+            nr_hw_queues,
+            queue_depth: 4,
+            max_io_buf_bytes: 64 << 10,
+            flags,
+            owner_uid: 0,
+            owner_gid: 0,
+        }
+    }
+
+    // Serves the queue 0 with `target` until the device is aborted
+    fn serve(dev: &MockCharDevice, target: &Arc<RamTarget>) -> JoinHandle<Result<()>> {
+        let dev = dev.clone();
+        let target = Arc::clone(target);
+        thread::spawn(move || {
+            let mut queue = UblkQueue::with_mock(&dev, 0)?;
+            drop(dev);
+            queue.submit_fetch_commands()?;
+            serve_queue(&mut queue, target.as_ref())
+        })
+    }
+
+    fn exec(dev: &MockCharDevice, q_id: u16, io: MockIo) -> MockCompletion {
+        let id = dev.submit(q_id, io).unwrap();
+        let completion = dev.wait_completion().unwrap();
+        assert_eq!(completion.id, id);
+        completion
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(13) ^ seed)
+            .collect()
+    }
+
+    #[test]
+    fn handle_io_ops() {
+        for flags in [DeviceFlags::empty(), DeviceFlags::UserCopy] {
+            let dev = MockCharDevice::new(&info(flags, 1)).unwrap();
+            let target = RamTarget::new();
+            let queue = serve(&dev, &target);
+
+            // FUA writes are flushed
+            let data = pattern(8 << 10, 0x3c);
+            let io = MockIo::write(8, data.clone()).flags(IoFlags::Fua);
+            assert_eq!(exec(&dev, 0, io).result, data.len() as i32);
+            assert_eq!(target.flushes.load(Ordering::Relaxed), 1);
+
+            let res = exec(&dev, 0, MockIo::read(8, 16));
+            assert_eq!(res.result, data.len() as i32);
+            assert_eq!(res.data, data);
+
+            assert_eq!(exec(&dev, 0, MockIo::flush()).result, 0);
+            assert_eq!(target.flushes.load(Ordering::Relaxed), 2);
+
+            assert_eq!(exec(&dev, 0, MockIo::discard(8, 8)).result, 0);
+            let res = exec(&dev, 0, MockIo::read(8, 8));
+            assert_eq!(res.data, [0; 4096]);
+
+            // The target's errors are committed as negative errno
+            let io = MockIo::new(IoOp::WriteZeroes, 0, 8);
+            assert_eq!(exec(&dev, 0, io).result, -libc::EOPNOTSUPP);
+            let res = exec(&dev, 0, MockIo::read(DISK_SIZE as u64 >> 9, 8));
+            assert_eq!(res.result, -libc::EINVAL);
+
+            dev.abort();
+            queue.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn handle_zone_ops() {
+        let dev =
+            MockCharDevice::new(&info(DeviceFlags::UserCopy | DeviceFlags::Zoned, 1)).unwrap();
+        let target = RamTarget::new();
+        let queue = serve(&dev, &target);
+
+        // Appends go at the zone's write pointer
+        for (idx, lba) in [ZONE_SECTORS, ZONE_SECTORS + 8].into_iter().enumerate() {
+            let data = pattern(4096, idx as u8);
+            let io = MockIo {
+                data: data.clone(),
+                ..MockIo::new(IoOp::ZoneAppend, ZONE_SECTORS, 8)
+            };
+            let res = exec(&dev, 0, io);
+            assert_eq!(res.result, data.len() as i32);
+            assert_eq!(res.zone_append_lba, lba);
+            assert_eq!(exec(&dev, 0, MockIo::read(lba, 8)).data, data);
+        }
+
+        // The zones past the end of the disk are zeroed
+        let nr_zones = (DISK_SIZE >> 9) / ZONE_SECTORS as usize;
+        let io = MockIo::new(IoOp::ReportZones, ZONE_SECTORS, nr_zones as u32);
+        let res = exec(&dev, 0, io);
+        assert_eq!(res.data.len(), nr_zones * 64);
+        let (zones, trailing) = res.data.split_at((nr_zones - 1) * 64);
+        let field = |desc: &[u8], idx: usize| {
+            u64::from_ne_bytes(desc[idx * 8..idx * 8 + 8].try_into().unwrap())
+        };
+        assert_eq!(field(&zones[..64], 0), ZONE_SECTORS);
+        assert_eq!(field(&zones[..64], 2), ZONE_SECTORS + 16);
+        assert_eq!(field(&zones[64..], 0), 2 * ZONE_SECTORS);
+        assert!(trailing.iter().all(|&b| b == 0));
+
+        dev.abort();
+        queue.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn spawn() {
+        let dev = MockCharDevice::new(&info(DeviceFlags::empty(), 2)).unwrap();
+        let target = RamTarget::new();
+
+        let queues = TargetDriver::new(*dev.info(), Arc::clone(&target))
+            .mock(dev.clone())
+            .spawn()
+            .unwrap();
+        assert!(dev.is_queue_ready(0) && dev.is_queue_ready(1));
+
+        let data = pattern(4096, 0x42);
+        assert_eq!(exec(&dev, 1, MockIo::write(0, data.clone())).result, 4096);
+        assert_eq!(exec(&dev, 0, MockIo::read(0, 8)).data, data);
+
+        dev.abort();
+        queues.join().unwrap();
+    }
+
+    #[test]
+    fn spawn_queue_failure() {
+        let dev = MockCharDevice::new(&info(DeviceFlags::empty(), 2)).unwrap();
+
+        // The queue 1 can't be attached twice
+        let queue = UblkQueue::with_mock(&dev, 1).unwrap();
+        let err = TargetDriver::new(*dev.info(), RamTarget::new())
+            .mock(dev.clone())
+            .spawn()
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+
+        // The queue 0 was closed
+        drop(queue);
+        UblkQueue::with_mock(&dev, 0).unwrap();
+    }
+
+    struct VecPool;
+
+    impl BufferPool for VecPool {
+        fn alloc(&mut self, len: usize) -> Vec<u8> {
+            vec![0; len]
+        }
+    }
+
+    #[test]
+    fn spawn_queue_panic() {
+        let dev = MockCharDevice::new(&info(DeviceFlags::empty(), 2)).unwrap();
+
+        let res = TargetDriver::new(*dev.info(), RamTarget::new())
+            .mock(dev.clone())
+            .buffer_pool(|q_id| {
+                assert_ne!(q_id, 1, "no buffer pool for the queue 1");
+                VecPool
+            })
+            .spawn();
+        assert!(matches!(
+            res,
+            Err(Error::QueuePanicked { dev_id: 0, q_id: 1 })
+        ));
+
+        // The queue 0 was closed
+        UblkQueue::with_mock(&dev, 0).unwrap();
+    }
+}
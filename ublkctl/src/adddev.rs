@@ -23,11 +23,39 @@ pub(crate) struct Opt {
     #[clap(long)]
     zero_copy: bool,
 
+    /// Register the request buffers as io_uring fixed buffers (zero copy)
+    #[clap(long)]
+    auto_buf_reg: bool,
+
     #[clap(long)]
     iou_comp_in_task: bool,
 
     #[clap(long)]
     need_get_data: bool,
+
+    /// Keep the device when the server dies, so it can be recovered
+    #[clap(long)]
+    user_recovery: bool,
+
+    /// Like --user-recovery, but re-issue the in-flight I/O to the new server
+    #[clap(long)]
+    user_recovery_reissue: bool,
+
+    /// Allow the device to be managed by its unprivileged owner
+    #[clap(long)]
+    unprivileged: bool,
+
+    /// Copy the request data through the char device (pread/pwrite)
+    #[clap(long)]
+    user_copy: bool,
+
+    /// Allow resizing the device while it's live
+    #[clap(long)]
+    update_size: bool,
+
+    /// Drop the requested features not supported by the kernel
+    #[clap(long)]
+    negotiate_features: bool,
 }
 
 pub(crate) fn add_device(opt: &Opt) {
@@ -51,6 +79,10 @@ pub(crate) fn add_device(opt: &Opt) {
         flags |= DeviceFlags::ZeroCopy;
     }
 
+    if opt.auto_buf_reg {
+        flags |= DeviceFlags::AutoBufReg;
+    }
+
     if opt.iou_comp_in_task {
         flags |= DeviceFlags::ForceIouCmdCompleteInTask;
     }
@@ -59,11 +91,32 @@ pub(crate) fn add_device(opt: &Opt) {
         flags |= DeviceFlags::NeedGetData
     }
 
+    if opt.user_recovery {
+        flags |= DeviceFlags::UserRecovery;
+    }
+
+    if opt.user_recovery_reissue {
+        flags |= DeviceFlags::UserRecovery | DeviceFlags::UserRecoveryReissue;
+    }
+
+    if opt.unprivileged {
+        flags |= DeviceFlags::Unprivileged;
+    }
+
+    if opt.user_copy {
+        flags |= DeviceFlags::UserCopy;
+    }
+
+    if opt.update_size {
+        flags |= DeviceFlags::UpdateSize;
+    }
+
     let mut options = DeviceOptions::new()
         .nr_hw_queues(num_queues)
         .queue_depth(queue_depth)
         .max_io_buf_bytes(max_io_buf_size)
-        .flags(flags);
+        .flags(flags)
+        .negotiate_features(opt.negotiate_features);
 
     if let Some(dev_id) = opt.device_id {
         options = options.device_id(dev_id);
@@ -78,19 +131,14 @@ pub(crate) fn add_device(opt: &Opt) {
 
     // let's add some example parameters
     let dev_size = 250 * 1024 * 1024 * 1024;
-    let params = DeviceParams {
-        attrs: Default::default(),
-        logical_bs_shift: 9,
-        physical_bs_shift: 12,
-        io_opt_shift: 12,
-        io_min_shift: 9,
-        max_sectors: info.max_io_buf_bytes >> 9, // dividing by the sector size (512)
-        dev_sectors: dev_size >> 9,              // dividing by the sector size (512)
-        ..Default::default()
-    };
-
-    ubctrl
-        .set_device_parameters(info.dev_id, &params)
+    let params = DeviceParams::builder(&info)
+        .size(dev_size)
+        .physical_block_size(4096)
+        .io_opt_size(4096)
+        .build();
+
+    params
+        .and_then(|params| ubctrl.set_device_parameters(info.dev_id, &params))
         .unwrap_or_else(|err| {
             eprintln!("{}", err);
             process::exit(1);
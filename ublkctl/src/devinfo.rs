@@ -4,8 +4,6 @@ use clap::Args;
 use std::process;
 use ublk::control::{DeviceInfo, DeviceParams, UblkCtrl};
 
-const MAX_NR_UBLK_DEVS: u32 = 128;
-
 #[derive(Args)]
 pub(crate) struct Opt {
     /// ublk device id [default: all ublk devices]
@@ -28,18 +26,34 @@ pub(crate) fn get_dev_info(opt: &Opt) {
     });
 
     if let Some(dev_id) = opt.device_id {
-        if let Err(err) = show_dev(&mut ubctrl, dev_id, opt.params, opt.affinity) {
+        if let Err(err) = ubctrl
+            .get_device_info(dev_id)
+            .and_then(|info| show_dev(&mut ubctrl, info, opt.params, opt.affinity))
+        {
             eprintln!("Error device ID {}: {}", dev_id, err);
         }
     } else {
-        for dev_id in 0..MAX_NR_UBLK_DEVS {
-            let _ = show_dev(&mut ubctrl, dev_id, opt.params, opt.affinity);
+        let dev_ids = UblkCtrl::list_devices().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        let infos = ubctrl.get_devices_info(&dev_ids).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+
+        for (dev_id, info) in dev_ids.into_iter().zip(infos) {
+            if let Err(err) =
+                info.and_then(|info| show_dev(&mut ubctrl, info, opt.params, opt.affinity))
+            {
+                eprintln!("Error device ID {}: {}", dev_id, err);
+            }
         }
     }
 }
 
-fn show_dev(uc: &mut UblkCtrl, dev_id: u32, params: bool, affinity: bool) -> ublk::Result<()> {
-    let info = uc.get_device_info(dev_id)?;
+fn show_dev(uc: &mut UblkCtrl, info: DeviceInfo, params: bool, affinity: bool) -> ublk::Result<()> {
+    let dev_id = info.dev_id;
     println!("\nDevice Info:");
     println!("============");
     println!("{}\n", dev_info_format(info));
@@ -63,8 +77,8 @@ fn show_dev(uc: &mut UblkCtrl, dev_id: u32, params: bool, affinity: bool) -> ubl
 }
 
 fn dev_info_format(info: DeviceInfo) -> String {
-    format!("\tDevice ID: {}\n\tServer PID: {}\n\tActive: {}\n\tNr. HW Queues: {}\n\tQueue depth: {}\n\tMax IO Buf: {} bytes\n\tflags: {:?}",
-            info.dev_id, info.srv_pid, info.active, info.nr_hw_queues, info.queue_depth, info.max_io_buf_bytes, info.flags)
+    format!("\tDevice ID: {}\n\tServer PID: {}\n\tActive: {}\n\tNr. HW Queues: {}\n\tQueue depth: {}\n\tMax IO Buf: {} bytes\n\tflags: {:?}\n\tOwner: {}:{}",
+            info.dev_id, info.srv_pid, info.active, info.nr_hw_queues, info.queue_depth, info.max_io_buf_bytes, info.flags, info.owner_uid, info.owner_gid)
 }
 
 fn dev_params_format(p: DeviceParams) -> String {
@@ -73,6 +87,9 @@ fn dev_params_format(p: DeviceParams) -> String {
     let basic = basic
         .replace("DeviceParams", "")
         .replace("DeviceParamDiscard", "")
+        .replace("DeviceParamDevt", "")
+        .replace("DeviceParamZoned", "")
+        .replace("DeviceParamSegment", "")
         .replace("{", "")
         .replace("}", "")
         .replace(',', "\n\t");
@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: MIT
+
+use clap::Args;
+use serde::Serialize;
+use std::{fs, process};
+use ublk::control::{DeviceInfo, DeviceParams, UblkCtrl};
+use ublk::queue::UblkQueue;
+
+#[derive(Args)]
+pub(crate) struct Opt {
+    /// Print the devices as a JSON array
+    #[clap(long)]
+    json: bool,
+}
+
+// One line of the listing
+#[derive(Serialize)]
+struct DevEntry {
+    #[serde(flatten)]
+    info: DeviceInfo,
+    srv_alive: bool,
+    // in bytes, `None` if the parameters are not set
+    size: Option<u64>,
+    // `None` until the device is started
+    block_dev: Option<String>,
+}
+
+pub(crate) fn list_devs(opt: &Opt) {
+    let mut ubctrl = UblkCtrl::new().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    let dev_ids = UblkCtrl::list_devices().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    let infos = ubctrl.get_devices_info(&dev_ids).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    let mut entries = Vec::with_capacity(dev_ids.len());
+    for (dev_id, info) in dev_ids.into_iter().zip(infos) {
+        match info {
+            Ok(info) => {
+                let params = ubctrl.get_device_parameters(dev_id).ok();
+                entries.push(dev_entry(info, params));
+            }
+            Err(err) => eprintln!("Error device ID {}: {}", dev_id, err),
+        }
+    }
+
+    if opt.json {
+        print_json(&entries);
+    } else {
+        print_table(&entries);
+    }
+}
+
+fn dev_entry(info: DeviceInfo, params: Option<DeviceParams>) -> DevEntry {
+    let size = params
+        .as_ref()
+        .map(|p| p.dev_sectors * u64::from(UblkQueue::SECTOR_SIZE));
+    let block_dev = params
+        .and_then(|p| p.devt)
+        .and_then(|devt| devt.disk_devt())
+        .and_then(block_dev_path);
+
+    DevEntry {
+        info,
+        srv_alive: is_alive(info.srv_pid),
+        size,
+        block_dev,
+    }
+}
+
+// Block device node of `devt`, as named by the kernel
+fn block_dev_path(devt: libc::dev_t) -> Option<String> {
+    let uevent = fs::read_to_string(format!(
+        "/sys/dev/block/{}:{}/uevent",
+        libc::major(devt),
+        libc::minor(devt)
+    ))
+    .ok()?;
+
+    uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("DEVNAME="))
+        .map(|name| format!("/dev/{}", name))
+}
+
+fn is_alive(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    // The process exists even if we are not allowed to signal it
+    let res = unsafe { libc::kill(pid, 0) };
+    res == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn flag_names(info: &DeviceInfo) -> Vec<&'static str> {
+    info.flags.iter_names().map(|(name, _)| name).collect()
+}
+
+fn print_table(entries: &[DevEntry]) {
+    println!(
+        "{:<6} {:<9} {:>8} {:<5} {:>6} {:>5} {:>8} {:<14} FLAGS",
+        "ID", "STATE", "PID", "ALIVE", "QUEUES", "DEPTH", "SIZE", "BLOCK"
+    );
+    for entry in entries {
+        let info = &entry.info;
+        let flags = flag_names(info);
+        println!(
+            "{:<6} {:<9} {:>8} {:<5} {:>6} {:>5} {:>8} {:<14} {}",
+            info.dev_id,
+            format!("{:?}", info.state),
+            info.srv_pid,
+            if entry.srv_alive { "yes" } else { "no" },
+            info.nr_hw_queues,
+            info.queue_depth,
+            entry.size.map_or_else(|| "-".to_string(), format_size),
+            entry.block_dev.as_deref().unwrap_or("-"),
+            if flags.is_empty() {
+                "-".to_string()
+            } else {
+                flags.join("|")
+            }
+        );
+    }
+}
+
+fn print_json(entries: &[DevEntry]) {
+    match serde_json::to_string_pretty(entries) {
+        Ok(json) => println!("{}", json),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// Size with the largest binary suffix that keeps it exact, as accepted by `resize`
+fn format_size(bytes: u64) -> String {
+    for (suffix, shift) in [("T", 40), ("G", 30), ("M", 20), ("K", 10)] {
+        if bytes >= 1 << shift && bytes.trailing_zeros() >= shift {
+            return format!("{}{}", bytes >> shift, suffix);
+        }
+    }
+    bytes.to_string()
+}
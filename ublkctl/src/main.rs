@@ -1,10 +1,16 @@
 use adddev::add_device;
 use clap::{Parser, Subcommand};
 use devinfo::get_dev_info;
+use listdev::list_devs;
+use recoverdev::recover_dev;
+use resizedev::resize_dev;
 use rmdev::remove_dev;
 
 mod adddev;
 mod devinfo;
+mod listdev;
+mod recoverdev;
+mod resizedev;
 mod rmdev;
 
 #[derive(Parser)]
@@ -28,6 +34,18 @@ enum CommandLineCommand {
     /// Get ublk device info
     #[command(name = "info")]
     GetDeviceInfo(devinfo::Opt),
+
+    /// List the ublk devices, one per line
+    #[command(name = "list")]
+    ListDevices(listdev::Opt),
+
+    /// Recover a quiesced ublk device relaunching its server
+    #[command(name = "recover")]
+    RecoverDevice(recoverdev::Opt),
+
+    /// Change the capacity of a live ublk device, its backing storage is not resized
+    #[command(name = "resize")]
+    ResizeDevice(resizedev::Opt),
 }
 
 fn main() {
@@ -37,5 +55,8 @@ fn main() {
         CommandLineCommand::AddDevice(o) => add_device(&o),
         CommandLineCommand::RemoveDevice(o) => remove_dev(&o),
         CommandLineCommand::GetDeviceInfo(o) => get_dev_info(&o),
+        CommandLineCommand::ListDevices(o) => list_devs(&o),
+        CommandLineCommand::RecoverDevice(o) => recover_dev(&o),
+        CommandLineCommand::ResizeDevice(o) => resize_dev(&o),
     }
 }
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: MIT
+
+use clap::Args;
+use std::process::{self, Command};
+use ublk::control::{DeviceState, UblkCtrl};
+
+#[derive(Args)]
+pub(crate) struct Opt {
+    /// ublk device id
+    #[clap(long)]
+    device_id: u32,
+
+    /// Server command line, the server must only serve the device queues
+    /// (the device id is passed in the UBLK_DEVICE_ID environment variable)
+    #[clap(required = true, last = true)]
+    server: Vec<String>,
+}
+
+pub(crate) fn recover_dev(opt: &Opt) {
+    let mut ubctrl = UblkCtrl::new().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    let dev_id = opt.device_id;
+    let info = ubctrl.get_device_info(dev_id).unwrap_or_else(|err| {
+        eprintln!("Error device ID {}: {}", dev_id, err);
+        process::exit(1);
+    });
+
+    if info.state != DeviceState::Quiesced {
+        eprintln!(
+            "Error device ID {}: not quiesced (state: {:?})",
+            dev_id, info.state
+        );
+        process::exit(1);
+    }
+
+    ubctrl.start_user_recovery(dev_id).unwrap_or_else(|err| {
+        eprintln!("Error device ID {}: {}", dev_id, err);
+        process::exit(1);
+    });
+
+    let mut server = Command::new(&opt.server[0])
+        .args(&opt.server[1..])
+        .env("UBLK_DEVICE_ID", dev_id.to_string())
+        .spawn()
+        .unwrap_or_else(|err| {
+            eprintln!("Error launching {}: {}", opt.server[0], err);
+            process::exit(1);
+        });
+
+    // The kernel driver waits for the server to submit the fetch commands of every queue
+    if let Err(err) = ubctrl.end_user_recovery(dev_id, u64::from(server.id())) {
+        eprintln!("Error device ID {}: {}", dev_id, err);
+        let _ = server.kill();
+        let _ = server.wait();
+        process::exit(1);
+    }
+
+    println!("Device {} recovered, server PID: {}", dev_id, server.id());
+
+    // Stay in the foreground until the server exits
+    let status = server.wait().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+    process::exit(status.code().unwrap_or(1));
+}
@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: MIT
+
+use clap::Args;
+use std::process;
+use ublk::control::UblkCtrl;
+use ublk::queue::UblkQueue;
+
+#[derive(Args)]
+pub(crate) struct Opt {
+    /// ublk device id
+    #[clap(long)]
+    device_id: u32,
+
+    /// New device size in bytes, K, M, G, T suffixes are allowed (e.g., 500G)
+    #[clap(long, value_parser = parse_size)]
+    size: u64,
+}
+
+// Only the device capacity is changed, the target serving the device is not
+// notified, as with a target not overriding `BlockTarget::resize()`
+pub(crate) fn resize_dev(opt: &Opt) {
+    let mut ubctrl = UblkCtrl::new().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1);
+    });
+
+    let sectors = opt.size / u64::from(UblkQueue::SECTOR_SIZE);
+    if let Err(err) = ubctrl.update_size(opt.device_id, sectors) {
+        eprintln!("Error device ID {}: {}", opt.device_id, err);
+        process::exit(1);
+    }
+}
+
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (num, shift) = match size.char_indices().last() {
+        Some((idx, 'K' | 'k')) => (&size[..idx], 10),
+        Some((idx, 'M' | 'm')) => (&size[..idx], 20),
+        Some((idx, 'G' | 'g')) => (&size[..idx], 30),
+        Some((idx, 'T' | 't')) => (&size[..idx], 40),
+        _ => (size, 0),
+    };
+
+    let bytes = num
+        .parse::<u64>()
+        .ok()
+        .and_then(|num| num.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size: {}", size))?;
+
+    if bytes % u64::from(UblkQueue::SECTOR_SIZE) != 0 {
+        return Err(format!(
+            "size must be a multiple of {} bytes",
+            UblkQueue::SECTOR_SIZE
+        ));
+    }
+
+    Ok(bytes)
+}
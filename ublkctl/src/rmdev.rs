@@ -4,8 +4,6 @@ use clap::Args;
 use std::process;
 use ublk::control::UblkCtrl;
 
-const MAX_NR_UBLK_DEVS: u32 = 128;
-
 #[derive(Args)]
 pub(crate) struct Opt {
     /// ublk device id [default: all ublk devices]
@@ -23,8 +21,19 @@ pub(crate) fn remove_dev(opt: &Opt) {
             eprintln!("Error device ID {}: {}", dev_id, err);
         }
     } else {
-        for dev_id in 0..MAX_NR_UBLK_DEVS {
-            let _ = ubctrl.delete_device(dev_id);
+        let dev_ids = UblkCtrl::list_devices().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            process::exit(1);
+        });
+        match ubctrl.delete_devices(&dev_ids) {
+            Ok(results) => {
+                for (dev_id, res) in dev_ids.into_iter().zip(results) {
+                    if let Err(err) = res {
+                        eprintln!("Error device ID {}: {}", dev_id, err);
+                    }
+                }
+            }
+            Err(err) => eprintln!("{}", err),
         }
     }
 }